@@ -0,0 +1,534 @@
+use std::{collections::HashMap, f64::consts::TAU};
+
+use crate::{
+    filter::{FilterChannel, FilterModel, FilterType},
+    instrument::Note,
+    io::{Stereo, WAV},
+    random::Random,
+};
+
+// Sine swept down from `start_frequency`, with a noise click on the beater hit
+#[derive(Debug, Clone, Copy)]
+pub struct Kick {
+    pub start_frequency: f32,
+    pub end_frequency: f32,
+    // Seconds the sweep takes to cover two thirds of the way
+    pub sweep: f32,
+    // Seconds to fall by 60 dB, the same for every drum
+    pub decay: f32,
+    pub click: f32,
+}
+
+impl Kick {
+    pub const DEFAULT: Kick = Kick {
+        start_frequency: 150.0,
+        end_frequency: 48.0,
+        sweep: 0.04,
+        decay: 0.5,
+        click: 0.3,
+    };
+}
+
+// Two drum head modes and high passed noise for the snares
+#[derive(Debug, Clone, Copy)]
+pub struct Snare {
+    pub frequency: f32,
+    pub tone_decay: f32,
+    pub noise_decay: f32,
+    // Share of noise in the mix, in [0, 1]
+    pub noise: f32,
+    // Hz
+    pub noise_cutoff: f32,
+}
+
+impl Snare {
+    pub const DEFAULT: Snare = Snare {
+        frequency: 185.0,
+        tone_decay: 0.15,
+        noise_decay: 0.25,
+        noise: 0.65,
+        noise_cutoff: 1500.0,
+    };
+}
+
+// Sine falling `pitch_drop` semitones onto `frequency`
+#[derive(Debug, Clone, Copy)]
+pub struct Tom {
+    pub frequency: f32,
+    pub pitch_drop: f32,
+    pub decay: f32,
+}
+
+impl Tom {
+    pub const DEFAULT: Tom = Tom {
+        frequency: 120.0,
+        pitch_drop: 4.0,
+        decay: 0.5,
+    };
+}
+
+/*
+ * Six detuned square waves, as on the TR-808, and noise through a high pass.
+ * Hi-hats and cymbals only differ by their settings.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Metal {
+    pub decay: f32,
+    // Hz
+    pub cutoff: f32,
+    // Share of noise in the mix, in [0, 1]
+    pub noise: f32,
+    // Factor on the square wave frequencies
+    pub tune: f32,
+}
+
+impl Metal {
+    pub const CLOSED_HI_HAT: Metal = Metal {
+        decay: 0.08,
+        cutoff: 7000.0,
+        noise: 0.4,
+        tune: 1.0,
+    };
+
+    pub const OPEN_HI_HAT: Metal = Metal {
+        decay: 0.6,
+        ..Metal::CLOSED_HI_HAT
+    };
+
+    pub const CYMBAL: Metal = Metal {
+        decay: 2.0,
+        cutoff: 4000.0,
+        noise: 0.6,
+        tune: 0.8,
+    };
+
+    // Frequencies of the TR-808 cymbal oscillators
+    const FREQUENCIES: [f64; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+}
+
+// Bursts of band passed noise a few milliseconds apart, then a tail
+#[derive(Debug, Clone, Copy)]
+pub struct Clap {
+    pub bursts: u8,
+    // Seconds between two bursts
+    pub spacing: f32,
+    pub decay: f32,
+    // Band pass centre in Hz
+    pub frequency: f32,
+}
+
+impl Clap {
+    pub const DEFAULT: Clap = Clap {
+        bursts: 3,
+        spacing: 0.011,
+        decay: 0.25,
+        frequency: 1200.0,
+    };
+}
+
+/*********************/
+#[derive(Debug, Clone, Copy)]
+pub enum Drum {
+    Kick(Kick),
+    Snare(Snare),
+    Tom(Tom),
+    HiHat(Metal),
+    Cymbal(Metal),
+    Clap(Clap),
+}
+
+impl Drum {
+    // Seconds from the hit until it falls silent
+    pub fn duration(&self) -> f32 {
+        match self {
+            Drum::Kick(kick) => kick.decay,
+            Drum::Snare(snare) => snare.tone_decay.max(snare.noise_decay),
+            Drum::Tom(tom) => tom.decay,
+            Drum::HiHat(metal) | Drum::Cymbal(metal) => metal.decay,
+            Drum::Clap(clap) => clap.bursts as f32 * clap.spacing + clap.decay,
+        }
+    }
+
+    // Sample `rt` seconds after the hit
+    fn sample(&self, rt: f64, state: &mut DrumState) -> f64 {
+        let dt = 1.0 / WAV::SAMPLE_RATE as f64;
+        let noise = state.random.next_f64() * 2.0 - 1.0;
+
+        match self {
+            Drum::Kick(kick) => {
+                let (start, end) = (kick.start_frequency as f64, kick.end_frequency as f64);
+                let f = end + (start - end) * (-rt / kick.sweep.max(1e-3) as f64).exp();
+
+                let tone = state.oscillator(0, f, dt).sin();
+                let click = noise * kick.click as f64 * fall(rt, 0.01);
+
+                (tone + click) * fall(rt, kick.decay)
+            }
+            Drum::Snare(snare) => {
+                // Second mode of a drum head at 1.59 times the first
+                let f = snare.frequency as f64;
+                let tone = state.oscillator(0, f, dt).sin() * 0.7
+                    + state.oscillator(1, f * 1.59, dt).sin() * 0.3;
+                let noise = state.filter(FilterType::HighPass, snare.noise_cutoff, 0.7, noise);
+                let mix = snare.noise.clamp(0.0, 1.0) as f64;
+
+                tone * (1.0 - mix) * fall(rt, snare.tone_decay)
+                    + noise * mix * fall(rt, snare.noise_decay)
+            }
+            Drum::Tom(tom) => {
+                let drop = 2f64.powf(tom.pitch_drop as f64 / 12.0) - 1.0;
+                let f = tom.frequency as f64 * (1.0 + drop * (-rt / 0.05).exp());
+                let tone = state.oscillator(0, f, dt).sin();
+
+                (tone + noise * 0.1 * fall(rt, 0.02)) * fall(rt, tom.decay)
+            }
+            Drum::HiHat(metal) | Drum::Cymbal(metal) => {
+                let squares = Metal::FREQUENCIES
+                    .iter()
+                    .enumerate()
+                    .map(|(i, f)| {
+                        let phase = state.oscillator(i, f * metal.tune as f64, dt);
+                        if phase < TAU / 2.0 {
+                            1.0
+                        } else {
+                            -1.0
+                        }
+                    })
+                    .sum::<f64>()
+                    / Metal::FREQUENCIES.len() as f64;
+
+                let mix = metal.noise.clamp(0.0, 1.0) as f64;
+                let v = squares * (1.0 - mix) + noise * mix;
+
+                // The high pass takes a lot of level away
+                state.filter(FilterType::HighPass, metal.cutoff, 0.7, v)
+                    * 2.0
+                    * fall(rt, metal.decay)
+            }
+            Drum::Clap(clap) => {
+                let spacing = clap.spacing.max(1e-3) as f64;
+                let tail = clap.bursts as f64 * spacing;
+                let level = if rt < tail {
+                    fall(rt % spacing, clap.spacing)
+                } else {
+                    fall(rt - tail, clap.decay)
+                };
+
+                state.filter(FilterType::BandPass, clap.frequency, 1.5, noise) * 2.0 * level
+            }
+        }
+    }
+}
+
+// Gain after `rt` seconds of a 60 dB fall over `decay` seconds
+fn fall(rt: f64, decay: f32) -> f64 {
+    10f64.powf(-3.0 * rt / decay.max(1e-3) as f64)
+}
+
+/*********************/
+// A drum and where it sits in the mix
+#[derive(Debug, Clone, Copy)]
+pub struct Pad {
+    pub drum: Drum,
+    pub level: f32,
+    // From -1 (left) to 1 (right)
+    pub pan: f32,
+}
+
+// Drums played by key, the pitch of a note only picks its pad
+#[derive(Debug, Clone)]
+pub struct DrumKit {
+    pub pads: HashMap<u8, Pad>,
+}
+
+impl DrumKit {
+    // General MIDI percussion keys 35 to 81, played on channel 10
+    pub fn general_midi() -> Self {
+        let kick = |start_frequency, end_frequency| {
+            Drum::Kick(Kick {
+                start_frequency,
+                end_frequency,
+                ..Kick::DEFAULT
+            })
+        };
+        let snare = |noise_cutoff| {
+            Drum::Snare(Snare {
+                noise_cutoff,
+                ..Snare::DEFAULT
+            })
+        };
+        let tom = |frequency| {
+            Drum::Tom(Tom {
+                frequency,
+                ..Tom::DEFAULT
+            })
+        };
+        // Pitched hits that don't fall, for wood and bells
+        let block = |frequency, decay| {
+            Drum::Tom(Tom {
+                frequency,
+                pitch_drop: 0.0,
+                decay,
+            })
+        };
+        let hand_drum = |frequency, decay| {
+            Drum::Tom(Tom {
+                frequency,
+                pitch_drop: 2.0,
+                decay,
+            })
+        };
+        let shaker = |decay, cutoff| {
+            Drum::HiHat(Metal {
+                decay,
+                cutoff,
+                noise: 1.0,
+                tune: 1.0,
+            })
+        };
+        let scrape = |bursts, spacing, frequency| {
+            Drum::Clap(Clap {
+                bursts,
+                spacing,
+                decay: 0.05,
+                frequency,
+            })
+        };
+        let cymbal = |decay, cutoff, tune| {
+            Drum::Cymbal(Metal {
+                decay,
+                cutoff,
+                tune,
+                ..Metal::CYMBAL
+            })
+        };
+
+        let pads = [
+            // Acoustic and electric bass drums
+            (35, kick(130.0, 42.0), 1.0, 0.0),
+            (36, kick(150.0, 48.0), 1.0, 0.0),
+            // Side stick
+            (37, block(800.0, 0.05), 0.5, 0.0),
+            (38, snare(1500.0), 0.8, 0.0),
+            (39, Drum::Clap(Clap::DEFAULT), 0.7, 0.0),
+            (40, snare(2500.0), 0.8, 0.0),
+            // Low floor tom to high tom
+            (41, tom(82.0), 0.8, -0.4),
+            (43, tom(98.0), 0.8, -0.3),
+            (45, tom(110.0), 0.8, -0.1),
+            (47, tom(131.0), 0.8, 0.1),
+            (48, tom(147.0), 0.8, 0.2),
+            (50, tom(175.0), 0.8, 0.3),
+            // Closed, pedal and open hi-hats
+            (42, Drum::HiHat(Metal::CLOSED_HI_HAT), 0.4, 0.3),
+            (
+                44,
+                Drum::HiHat(Metal {
+                    decay: 0.12,
+                    ..Metal::CLOSED_HI_HAT
+                }),
+                0.35,
+                0.3,
+            ),
+            (46, Drum::HiHat(Metal::OPEN_HI_HAT), 0.4, 0.3),
+            // Crashes, rides, bell, chinese and splash
+            (49, cymbal(2.0, 4000.0, 0.8), 0.45, -0.3),
+            (57, cymbal(2.2, 3800.0, 0.75), 0.45, 0.4),
+            (51, cymbal(3.0, 6000.0, 1.2), 0.3, 0.4),
+            (59, cymbal(3.0, 6500.0, 1.3), 0.3, -0.4),
+            (53, cymbal(1.5, 3000.0, 2.0), 0.35, 0.4),
+            (52, cymbal(1.5, 2500.0, 0.6), 0.45, -0.4),
+            (55, cymbal(0.8, 5000.0, 1.1), 0.45, -0.2),
+            // Tambourine, cowbell and vibraslap
+            (
+                54,
+                Drum::HiHat(Metal {
+                    decay: 0.25,
+                    cutoff: 8000.0,
+                    noise: 0.7,
+                    tune: 1.6,
+                }),
+                0.35,
+                0.5,
+            ),
+            (56, block(560.0, 0.3), 0.4, 0.3),
+            (58, scrape(12, 0.03, 3000.0), 0.4, -0.3),
+            // High and low bongos, muted, open and low congas
+            (60, hand_drum(400.0, 0.2), 0.7, 0.3),
+            (61, hand_drum(300.0, 0.25), 0.7, 0.2),
+            (62, hand_drum(330.0, 0.08), 0.7, -0.2),
+            (63, hand_drum(330.0, 0.3), 0.7, -0.2),
+            (64, hand_drum(220.0, 0.35), 0.7, -0.3),
+            // High and low timbales, agogos
+            (65, block(500.0, 0.4), 0.6, 0.4),
+            (66, block(370.0, 0.45), 0.6, 0.3),
+            (67, block(900.0, 0.3), 0.4, 0.5),
+            (68, block(675.0, 0.3), 0.4, 0.5),
+            // Cabasa and maracas
+            (69, shaker(0.1, 6000.0), 0.3, -0.5),
+            (70, shaker(0.05, 9000.0), 0.3, 0.5),
+            // Short and long whistles, guiros
+            (71, block(2300.0, 0.15), 0.3, 0.0),
+            (72, block(2300.0, 0.5), 0.3, 0.0),
+            (73, scrape(4, 0.012, 2500.0), 0.4, -0.4),
+            (74, scrape(12, 0.012, 2500.0), 0.4, -0.4),
+            // Claves, high and low wood blocks
+            (75, block(2500.0, 0.06), 0.5, 0.2),
+            (76, block(1100.0, 0.08), 0.5, 0.3),
+            (77, block(800.0, 0.08), 0.5, 0.3),
+            // Muted and open cuicas, rising onto their pitch
+            (
+                78,
+                Drum::Tom(Tom {
+                    frequency: 500.0,
+                    pitch_drop: -7.0,
+                    decay: 0.15,
+                }),
+                0.5,
+                -0.1,
+            ),
+            (
+                79,
+                Drum::Tom(Tom {
+                    frequency: 500.0,
+                    pitch_drop: -7.0,
+                    decay: 0.4,
+                }),
+                0.5,
+                -0.1,
+            ),
+            // Muted and open triangles
+            (80, cymbal(0.2, 4000.0, 6.0), 0.25, 0.6),
+            (81, cymbal(1.5, 4000.0, 6.0), 0.25, 0.6),
+        ];
+
+        Self {
+            pads: pads
+                .into_iter()
+                .map(|(key, drum, level, pan)| (key, Pad { drum, level, pan }))
+                .collect(),
+        }
+    }
+
+    pub fn play(&self, t: f32, note: &Note, state: &mut DrumState) -> Stereo {
+        let Some(pad) = self.pads.get(&note.pitch.key()) else {
+            return Stereo::default();
+        };
+
+        if !state.started {
+            *state = DrumState::new(note.seed());
+        }
+
+        let rt = (t - note.start_time) as f64;
+        let v = pad.drum.sample(rt, state) * pad.level as f64;
+
+        Stereo::pan(v as f32, pad.pan)
+    }
+
+    // Drums ring out whatever the length of the note
+    pub fn is_active(&self, t: f32, note: &Note) -> bool {
+        self.pads
+            .get(&note.pitch.key())
+            .is_some_and(|pad| t < note.start_time + pad.drum.duration())
+    }
+}
+
+/*********************/
+// Per voice state of a DrumKit
+#[derive(Debug, Clone)]
+pub struct DrumState {
+    started: bool,
+    random: Random,
+    // Radians, one per oscillator
+    phases: [f64; 6],
+    filter: Option<FilterChannel>,
+}
+
+impl DrumState {
+    fn new(seed: u64) -> Self {
+        Self {
+            started: true,
+            random: Random::new(seed),
+            phases: [0.0; 6],
+            filter: None,
+        }
+    }
+
+    // Phase of oscillator `i`, then advanced by a sample of `f`
+    fn oscillator(&mut self, i: usize, f: f64, dt: f64) -> f64 {
+        let phase = self.phases[i];
+        self.phases[i] = (phase + TAU * f * dt) % TAU;
+
+        phase
+    }
+
+    fn filter(&mut self, kind: FilterType, cutoff: f32, resonance: f32, v: f64) -> f64 {
+        self.filter
+            .get_or_insert_with(|| {
+                FilterChannel::new(FilterModel::StateVariable, kind, cutoff, resonance)
+            })
+            .run(v as f32) as f64
+    }
+}
+
+impl Default for DrumState {
+    fn default() -> Self {
+        Self {
+            started: false,
+            random: Random::new(0),
+            phases: [0.0; 6],
+            filter: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instrument::Instrument,
+        pitch::Pitch,
+        tuning::{KeyboardMapping, Scale, Tuning},
+    };
+
+    #[test]
+    fn general_midi_maps_every_key() {
+        let kit = DrumKit::general_midi();
+
+        for key in 35..=81 {
+            assert!(kit.pads.contains_key(&key), "no pad on key {}", key);
+        }
+        assert_eq!(kit.pads.len(), 47);
+    }
+
+    #[test]
+    fn drums_ignore_the_tuning() {
+        let kit = DrumKit::general_midi();
+        let instrument = Instrument {
+            drums: Some(&kit),
+            ..Instrument::DEFAULT
+        };
+
+        // Maps nothing but the reference key
+        let mut map = vec![None; 12];
+        map[9] = Some(0);
+        let mapping = KeyboardMapping {
+            map,
+            ..KeyboardMapping::linear()
+        };
+        let tuning = Tuning::new(Scale::equal(12).unwrap(), mapping).unwrap();
+        assert_eq!(tuning.frequency(Pitch::D2), None);
+
+        // Acoustic snare
+        let mut note = Note::new(Pitch::D2, 1.0, 0.0, instrument, 1.0);
+        let peak = (0..WAV::SAMPLE_RATE / 10)
+            .map(|n| {
+                note.play(n as f32 / WAV::SAMPLE_RATE as f32, &tuning)
+                    .left
+                    .abs()
+            })
+            .fold(0.0, f32::max);
+
+        assert!(peak > 0.1, "peak {}", peak);
+    }
+}
@@ -0,0 +1,408 @@
+use biquad::{Biquad, Coefficients, DirectForm1, ToHertz, Type};
+
+use crate::{
+    instrument::{Envelope, Note},
+    io::{Stereo, WAV},
+    modulation::Modulations,
+    pitch::Pitch,
+    track::Effect,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum FilterType {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+// Circuit a Filter is modelled on
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum FilterModel {
+    // 12 dB/octave biquad, best left unmodulated
+    Biquad,
+    // Zero-delay-feedback state variable filter, 12 dB/octave
+    StateVariable,
+    // Moog style 24 dB/octave low pass whatever the type, its input clips harder as `drive` grows
+    Ladder { drive: f32 },
+}
+
+/*********************/
+/*
+ * Filter settings of an instrument, every voice runs its own filter built
+ * from them and follows its note, envelope and modulations
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Filter<'a> {
+    pub model: FilterModel,
+    pub kind: FilterType,
+    // Hz, for a C4 when key tracking
+    pub cutoff: f32,
+    // Q
+    pub resonance: f32,
+    // Octaves the cutoff moves per octave played, 1 to follow the keyboard
+    pub key_tracking: f32,
+    pub envelope: Option<&'a Envelope>,
+    // Octaves the envelope opens the cutoff by at full level
+    pub envelope_depth: f32,
+}
+
+impl Default for Filter<'_> {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl<'a> Filter<'a> {
+    // Butterworth low pass at 1 kHz, fixed whatever the note
+    pub const DEFAULT: Filter<'a> = Filter {
+        model: FilterModel::Biquad,
+        kind: FilterType::LowPass,
+        cutoff: 1000.0,
+        resonance: biquad::Q_BUTTERWORTH_F32,
+        key_tracking: 0.0,
+        envelope: None,
+        envelope_depth: 0.0,
+    };
+
+    // Samples between two coefficient updates
+    pub const CONTROL_INTERVAL: u32 = 32;
+
+    // Cutoff at `t` of a note played on `f`
+    pub fn cutoff(&self, t: f32, f: f32, note: &Note, m: &Modulations) -> f32 {
+        let mut octaves = m.cutoff;
+
+        octaves += self.key_tracking * (f / Pitch::C4.frequency()).log2();
+
+        if let Some(e) = self.envelope {
+            octaves += e.play(t, note) * self.envelope_depth;
+        }
+
+        (self.cutoff * 2f32.powf(octaves)).clamp(10.0, WAV::SAMPLE_RATE as f32 * 0.49)
+    }
+
+    pub fn resonance(&self, m: &Modulations) -> f32 {
+        (self.resonance + m.resonance).max(0.1)
+    }
+
+    // Filters one frame, the coefficients follow at control rate
+    pub fn run(
+        &self,
+        v: Stereo,
+        t: f32,
+        f: f32,
+        note: &Note,
+        m: &Modulations,
+        state: &mut FilterState,
+    ) -> Stereo {
+        if state.countdown == 0 {
+            let (cutoff, resonance) = (self.cutoff(t, f, note, m), self.resonance(m));
+
+            match &mut state.channels {
+                Some(channels) => channels
+                    .iter_mut()
+                    .for_each(|c| c.set(self.kind, cutoff, resonance)),
+                None => {
+                    state.channels =
+                        Some([FilterChannel::new(self.model, self.kind, cutoff, resonance); 2])
+                }
+            }
+
+            state.countdown = Self::CONTROL_INTERVAL;
+        }
+        state.countdown -= 1;
+
+        match &mut state.channels {
+            Some([left, right]) => Stereo::new(left.run(v.left), right.run(v.right)),
+            None => v,
+        }
+    }
+}
+
+// Per voice state of a Filter
+#[derive(Debug, Clone, Default)]
+pub struct FilterState {
+    channels: Option<[FilterChannel; 2]>,
+    // Samples left until the next coefficient update
+    countdown: u32,
+}
+
+/*********************/
+// Filter on the mix of a whole track
+#[derive(Debug, Clone, Copy)]
+pub struct FilterEffect {
+    channels: [FilterChannel; 2],
+}
+
+#[allow(dead_code)]
+impl FilterEffect {
+    pub fn new(model: FilterModel, kind: FilterType, cutoff: f32, resonance: f32) -> Self {
+        Self {
+            channels: [FilterChannel::new(model, kind, cutoff, resonance); 2],
+        }
+    }
+
+    // Can be called on every frame to automate the filter
+    pub fn set(&mut self, kind: FilterType, cutoff: f32, resonance: f32) {
+        self.channels
+            .iter_mut()
+            .for_each(|c| c.set(kind, cutoff, resonance));
+    }
+}
+
+impl Effect for FilterEffect {
+    fn process(&mut self, v: Stereo) -> Stereo {
+        let [left, right] = &mut self.channels;
+
+        Stereo::new(left.run(v.left), right.run(v.right))
+    }
+}
+
+/*********************/
+// One channel of any filter model
+#[derive(Debug, Clone, Copy)]
+pub enum FilterChannel {
+    Biquad(DirectForm1<f32>),
+    StateVariable(FilterType, StateVariable),
+    Ladder(Ladder),
+}
+
+impl FilterChannel {
+    pub fn new(model: FilterModel, kind: FilterType, cutoff: f32, resonance: f32) -> Self {
+        let mut channel = match model {
+            FilterModel::Biquad => FilterChannel::Biquad(DirectForm1::<f32>::new(
+                Self::coefficients(kind, cutoff, resonance),
+            )),
+            FilterModel::StateVariable => {
+                FilterChannel::StateVariable(kind, StateVariable::default())
+            }
+            FilterModel::Ladder { drive } => FilterChannel::Ladder(Ladder::new(drive)),
+        };
+
+        channel.set(kind, cutoff, resonance);
+        channel
+    }
+
+    // New cutoff in Hz and Q, the filter memory is kept
+    pub fn set(&mut self, kind: FilterType, cutoff: f32, resonance: f32) {
+        let (cutoff, resonance) = Self::limit(cutoff, resonance);
+
+        match self {
+            FilterChannel::Biquad(filter) => {
+                filter.update_coefficients(Self::coefficients(kind, cutoff, resonance))
+            }
+            FilterChannel::StateVariable(k, filter) => {
+                *k = kind;
+                filter.set(cutoff, resonance);
+            }
+            FilterChannel::Ladder(filter) => filter.set(cutoff, resonance),
+        }
+    }
+
+    pub fn run(&mut self, v: f32) -> f32 {
+        match self {
+            FilterChannel::Biquad(filter) => filter.run(v),
+            FilterChannel::StateVariable(kind, filter) => filter.run(v, *kind),
+            FilterChannel::Ladder(filter) => filter.run(v),
+        }
+    }
+
+    // Cutoff under Nyquist and Q above 0, where every model is defined
+    fn limit(cutoff: f32, resonance: f32) -> (f32, f32) {
+        (
+            cutoff.clamp(10.0, WAV::SAMPLE_RATE as f32 * 0.49),
+            resonance.max(0.1),
+        )
+    }
+
+    fn coefficients(kind: FilterType, cutoff: f32, resonance: f32) -> Coefficients<f32> {
+        let (cutoff, resonance) = Self::limit(cutoff, resonance);
+        let kind = match kind {
+            FilterType::LowPass => Type::LowPass,
+            FilterType::HighPass => Type::HighPass,
+            FilterType::BandPass => Type::BandPass,
+            FilterType::Notch => Type::Notch,
+        };
+
+        Coefficients::<f32>::from_params(
+            kind,
+            (WAV::SAMPLE_RATE as f32).hz(),
+            cutoff.hz(),
+            resonance,
+        )
+        .expect("the cutoff is kept under Nyquist and the Q positive")
+    }
+}
+
+/*********************/
+/*
+ * Trapezoidal state variable filter (Andrew Simper, Cytomic). Its state is
+ * the current through two capacitors, so it stays stable when the cutoff jumps.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StateVariable {
+    ic1eq: f64,
+    ic2eq: f64,
+    k: f64,
+    a1: f64,
+    a2: f64,
+    a3: f64,
+}
+
+impl StateVariable {
+    pub fn set(&mut self, cutoff: f32, resonance: f32) {
+        let g = (std::f64::consts::PI * cutoff as f64 / WAV::SAMPLE_RATE as f64).tan();
+
+        self.k = 1.0 / resonance as f64;
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    pub fn run(&mut self, v: f32, kind: FilterType) -> f32 {
+        let v0 = v as f64;
+        let v3 = v0 - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let (low, band) = (v2, v1);
+        let high = v0 - self.k * band - low;
+
+        (match kind {
+            FilterType::LowPass => low,
+            FilterType::HighPass => high,
+            FilterType::BandPass => band,
+            FilterType::Notch => low + high,
+        }) as f32
+    }
+}
+
+/*********************/
+/*
+ * Four trapezoidal one pole stages with their feedback solved without a unit
+ * delay (Vadim Zavalishin, The Art of VA Filter Design), and a tanh input stage
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Ladder {
+    pub drive: f32,
+    s: [f64; 4],
+    g: f64,
+    // Feedback, self oscillates at 4
+    k: f64,
+}
+
+impl Ladder {
+    pub fn new(drive: f32) -> Self {
+        Self {
+            drive,
+            s: [0.0; 4],
+            g: 0.0,
+            k: 0.0,
+        }
+    }
+
+    // Q maps to the feedback, 0.5 has none and it nears self oscillation as Q grows
+    pub fn set(&mut self, cutoff: f32, resonance: f32) {
+        let g = (std::f64::consts::PI * cutoff as f64 / WAV::SAMPLE_RATE as f64).tan();
+
+        self.g = g / (1.0 + g);
+        self.k = (4.0 * (1.0 - 0.5 / resonance as f64)).clamp(0.0, 3.99);
+    }
+
+    pub fn run(&mut self, v: f32) -> f32 {
+        let g = self.g;
+
+        // Each stage outputs g * x + s / (1 + g), so the last one is G * x + S
+        let big_g = g * g * g * g;
+        let big_s = self.s.iter().fold(0.0, |acc, s| acc * g + s * (1.0 - g));
+
+        // Unity gain for small signals, softly clipped as they grow
+        let drive = self.drive.max(f32::EPSILON) as f64;
+        let input = (v as f64 * drive).tanh() / drive;
+        let y = (big_g * input + big_s) / (1.0 + self.k * big_g);
+
+        let mut x = input - self.k * y;
+        for s in self.s.iter_mut() {
+            let v = (x - *s) * g;
+            x = v + *s;
+            *s = x + v;
+        }
+
+        x as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+
+    const MODELS: [FilterModel; 3] = [
+        FilterModel::Biquad,
+        FilterModel::StateVariable,
+        FilterModel::Ladder { drive: 2.0 },
+    ];
+    const TYPES: [FilterType; 4] = [
+        FilterType::LowPass,
+        FilterType::HighPass,
+        FilterType::BandPass,
+        FilterType::Notch,
+    ];
+
+    #[test]
+    fn out_of_range_settings_are_clamped() {
+        for model in MODELS {
+            for (cutoff, resonance) in
+                [(30000.0, 0.7), (-100.0, 0.7), (1000.0, 0.0), (1000.0, -1.0)]
+            {
+                let mut filter = FilterEffect::new(model, FilterType::LowPass, cutoff, resonance);
+                filter.set(FilterType::HighPass, cutoff, resonance);
+
+                for n in 0..1000 {
+                    let v = filter.process(Stereo::new((n % 50) as f32 / 50.0, 0.0));
+                    assert!(
+                        v.left.is_finite(),
+                        "{:?} at {} Hz, Q {}",
+                        model,
+                        cutoff,
+                        resonance
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fast_cutoff_modulation_stays_stable() {
+        let mut random = Random::new(0);
+
+        for model in [
+            FilterModel::StateVariable,
+            FilterModel::Ladder { drive: 2.0 },
+        ] {
+            for kind in TYPES {
+                let mut channel = FilterChannel::new(model, kind, 1000.0, 8.0);
+                let mut peak = 0.0f32;
+
+                // A new cutoff between 20 Hz and 20 kHz on every sample, fed a square
+                for n in 0..WAV::SAMPLE_RATE {
+                    let cutoff = 20.0 * 1000f32.powf(random.next_f64() as f32);
+                    channel.set(kind, cutoff, 8.0);
+
+                    let v = if n % 100 < 50 { 0.5 } else { -0.5 };
+                    let y = channel.run(v);
+
+                    assert!(y.is_finite(), "{:?} {:?} at sample {}", model, kind, n);
+                    peak = peak.max(y.abs());
+                }
+
+                assert!(peak < 10.0, "{:?} {:?} peaked at {}", model, kind, peak);
+            }
+        }
+    }
+}
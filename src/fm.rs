@@ -0,0 +1,349 @@
+use std::f64::consts::TAU;
+
+use crate::{
+    instrument::{Envelope, Note},
+    io::WAV,
+};
+
+// What an operator runs at, relative to the note or in Hz
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum OperatorFrequency {
+    Ratio(f32),
+    Fixed(f32),
+}
+
+// How modulators act on the operators they're routed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Modulation {
+    // Offsets the phase, like the DX7
+    Phase,
+    // Deviates the frequency by the modulator output times its frequency
+    Frequency,
+}
+
+/*********************/
+// Sine oscillator of an FM instrument
+#[derive(Debug, Clone, Copy)]
+pub struct Operator<'a> {
+    pub frequency: OperatorFrequency,
+    // Cents
+    pub detune: f32,
+    // Amplitude when a carrier, modulation index in radians when a modulator
+    pub level: f32,
+    pub envelope: Option<&'a Envelope>,
+}
+
+impl Default for Operator<'_> {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl<'a> Operator<'a> {
+    // Sine at full level on the note frequency, without envelope
+    pub const DEFAULT: Operator<'a> = Operator {
+        frequency: OperatorFrequency::Ratio(1.0),
+        detune: 0.0,
+        level: 1.0,
+        envelope: None,
+    };
+
+    fn frequency(&self, f: f64) -> f64 {
+        let f = match self.frequency {
+            OperatorFrequency::Ratio(r) => f * r as f64,
+            OperatorFrequency::Fixed(hz) => hz as f64,
+        };
+
+        f * 2f64.powf(self.detune as f64 / 1200.0)
+    }
+}
+
+/*********************/
+/*
+ * Modulator -> carrier graph over operators numbered from 0. Cycles are
+ * allowed, the modulator then acts with the output of the previous sample.
+ */
+#[derive(Debug, Clone)]
+pub struct Algorithm {
+    // (modulator, modulated) pairs
+    pub routes: Vec<(usize, usize)>,
+    // Operators heard in the output
+    pub carriers: Vec<usize>,
+    // (from, to) feedback loop, to itself for an operator feeding back alone
+    pub feedback: Option<(usize, usize)>,
+}
+
+#[allow(dead_code)]
+impl Algorithm {
+    // Operator n modulates n - 1 and so on down to the single carrier 0
+    pub fn stack(operators: usize) -> Self {
+        Self {
+            routes: (1..operators).map(|i| (i, i - 1)).collect(),
+            carriers: vec![0],
+            feedback: operators.checked_sub(1).map(|last| (last, last)),
+        }
+    }
+
+    // Every operator is a carrier, additive synthesis
+    pub fn parallel(operators: usize) -> Self {
+        Self {
+            routes: vec![],
+            carriers: (0..operators).collect(),
+            feedback: None,
+        }
+    }
+
+    /*
+     * DX7 algorithm 1 to 32 on six operators, numbered like on the synth
+     * minus one: the DX7's operator 1 is operator 0 here
+     */
+    pub fn dx7(algorithm: u8) -> Option<Self> {
+        type Routes = &'static [(usize, usize)];
+
+        // Routes, carriers and feedback, in DX7 operator numbers
+        let (routes, carriers, feedback): (Routes, &[usize], (usize, usize)) = match algorithm {
+            1 => (&[(2, 1), (4, 3), (5, 4), (6, 5)], &[1, 3], (6, 6)),
+            2 => (&[(2, 1), (4, 3), (5, 4), (6, 5)], &[1, 3], (2, 2)),
+            3 => (&[(2, 1), (3, 2), (5, 4), (6, 5)], &[1, 4], (6, 6)),
+            4 => (&[(2, 1), (3, 2), (5, 4), (6, 5)], &[1, 4], (4, 6)),
+            5 => (&[(2, 1), (4, 3), (6, 5)], &[1, 3, 5], (6, 6)),
+            6 => (&[(2, 1), (4, 3), (6, 5)], &[1, 3, 5], (5, 6)),
+            7 => (&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], (6, 6)),
+            8 => (&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], (4, 4)),
+            9 => (&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], (2, 2)),
+            10 => (&[(2, 1), (3, 2), (5, 4), (6, 4)], &[1, 4], (3, 3)),
+            11 => (&[(2, 1), (3, 2), (5, 4), (6, 4)], &[1, 4], (6, 6)),
+            12 => (&[(2, 1), (4, 3), (5, 3), (6, 3)], &[1, 3], (2, 2)),
+            13 => (&[(2, 1), (4, 3), (5, 3), (6, 3)], &[1, 3], (6, 6)),
+            14 => (&[(2, 1), (4, 3), (5, 4), (6, 4)], &[1, 3], (6, 6)),
+            15 => (&[(2, 1), (4, 3), (5, 4), (6, 4)], &[1, 3], (2, 2)),
+            16 => (&[(2, 1), (3, 1), (5, 1), (4, 3), (6, 5)], &[1], (6, 6)),
+            17 => (&[(2, 1), (3, 1), (5, 1), (4, 3), (6, 5)], &[1], (2, 2)),
+            18 => (&[(2, 1), (3, 1), (4, 1), (5, 4), (6, 5)], &[1], (3, 3)),
+            19 => (&[(2, 1), (3, 2), (6, 4), (6, 5)], &[1, 4, 5], (6, 6)),
+            20 => (&[(3, 1), (3, 2), (5, 4), (6, 4)], &[1, 2, 4], (3, 3)),
+            21 => (&[(3, 1), (3, 2), (6, 4), (6, 5)], &[1, 2, 4, 5], (3, 3)),
+            22 => (&[(2, 1), (6, 3), (6, 4), (6, 5)], &[1, 3, 4, 5], (6, 6)),
+            23 => (&[(3, 2), (6, 4), (6, 5)], &[1, 2, 4, 5], (6, 6)),
+            24 => (&[(6, 3), (6, 4), (6, 5)], &[1, 2, 3, 4, 5], (6, 6)),
+            25 => (&[(6, 4), (6, 5)], &[1, 2, 3, 4, 5], (6, 6)),
+            26 => (&[(3, 2), (5, 4), (6, 4)], &[1, 2, 4], (6, 6)),
+            27 => (&[(3, 2), (5, 4), (6, 4)], &[1, 2, 4], (3, 3)),
+            28 => (&[(2, 1), (4, 3), (5, 4)], &[1, 3, 6], (5, 5)),
+            29 => (&[(4, 3), (6, 5)], &[1, 2, 3, 5], (6, 6)),
+            30 => (&[(4, 3), (5, 4)], &[1, 2, 3, 6], (5, 5)),
+            31 => (&[(6, 5)], &[1, 2, 3, 4, 5], (6, 6)),
+            32 => (&[], &[1, 2, 3, 4, 5, 6], (6, 6)),
+            _ => return None,
+        };
+
+        Some(Self {
+            routes: routes.iter().map(|(m, c)| (m - 1, c - 1)).collect(),
+            carriers: carriers.iter().map(|c| c - 1).collect(),
+            feedback: Some((feedback.0 - 1, feedback.1 - 1)),
+        })
+    }
+
+    // Order operators so modulators come before what they modulate
+    fn order(&self, operators: usize) -> Vec<usize> {
+        fn visit(i: usize, routes: &[(usize, usize)], seen: &mut [bool], order: &mut Vec<usize>) {
+            if seen[i] {
+                return;
+            }
+            seen[i] = true;
+
+            for (m, _) in routes.iter().filter(|(_, c)| *c == i) {
+                if *m < seen.len() {
+                    visit(*m, routes, seen, order);
+                }
+            }
+
+            order.push(i);
+        }
+
+        let mut seen = vec![false; operators];
+        let mut order = vec![];
+
+        for i in 0..operators {
+            visit(i, &self.routes, &mut seen, &mut order);
+        }
+
+        order
+    }
+}
+
+/*********************/
+#[derive(Debug, Clone)]
+pub struct FM<'a> {
+    pub operators: Vec<Operator<'a>>,
+    pub algorithm: Algorithm,
+    pub modulation: Modulation,
+    // Modulation index of the feedback loop, in radians
+    pub feedback: f32,
+}
+
+impl FM<'_> {
+    pub fn play(&self, t: f32, f: f64, note: &Note, state: &mut FMState) -> f32 {
+        let n = self.operators.len();
+
+        if state.phases.len() != n {
+            *state = FMState {
+                phases: vec![0.0; n],
+                outputs: vec![0.0; n],
+                feedback: [0.0; 2],
+                order: self.algorithm.order(n),
+            };
+        }
+
+        // Phase offset, or phase increment when modulating the frequency
+        let amount = |output: f64, f: f64| match self.modulation {
+            Modulation::Phase => output / TAU,
+            Modulation::Frequency => output * f / WAV::SAMPLE_RATE as f64,
+        };
+
+        for &i in &state.order {
+            let operator = &self.operators[i];
+            let operator_f = operator.frequency(f);
+
+            // Modulators evaluated later in the order still hold the previous sample
+            let mut modulation = 0.0;
+            for (m, _) in self.algorithm.routes.iter().filter(|(_, c)| *c == i) {
+                if let (Some(output), Some(modulator)) =
+                    (state.outputs.get(*m), self.operators.get(*m))
+                {
+                    modulation += amount(*output, modulator.frequency(f));
+                }
+            }
+
+            // Averaging the last two outputs tames the feedback like the DX7 does
+            if let Some((from, to)) = self.algorithm.feedback {
+                if to == i && from < n {
+                    let output =
+                        self.feedback as f64 * (state.feedback[0] + state.feedback[1]) / 2.0;
+                    modulation += amount(output, self.operators[from].frequency(f));
+                }
+            }
+
+            let level = operator.level
+                * match operator.envelope {
+                    Some(e) => e.play(t, note),
+                    None => 1.0,
+                };
+
+            let phase = match self.modulation {
+                Modulation::Phase => state.phases[i] + modulation,
+                Modulation::Frequency => state.phases[i],
+            };
+            state.outputs[i] = (TAU * phase).sin() * level as f64;
+
+            let dt = operator_f / WAV::SAMPLE_RATE as f64;
+            state.phases[i] = match self.modulation {
+                Modulation::Phase => state.phases[i] + dt,
+                Modulation::Frequency => state.phases[i] + dt + modulation,
+            }
+            .rem_euclid(1.0);
+
+            if self.algorithm.feedback.is_some_and(|(from, _)| from == i) {
+                state.feedback = [state.outputs[i], state.feedback[0]];
+            }
+        }
+
+        self.algorithm
+            .carriers
+            .iter()
+            .filter_map(|c| state.outputs.get(*c))
+            .sum::<f64>() as f32
+    }
+
+    // Sounding while any carrier envelope is
+    pub fn is_active(&self, t: f32, note: &Note) -> bool {
+        self.algorithm.carriers.iter().any(|c| {
+            self.operators
+                .get(*c)
+                .and_then(|o| o.envelope)
+                .is_some_and(|e| e.is_active(t, note))
+        })
+    }
+}
+
+// Per voice state of an FM instrument
+#[derive(Debug, Clone, Default)]
+pub struct FMState {
+    phases: Vec<f64>,
+    outputs: Vec<f64>,
+    // Last two outputs of the operator feeding back
+    feedback: [f64; 2],
+    order: Vec<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instrument::Instrument, pitch::Pitch};
+
+    #[test]
+    fn dx7_modulators_run_before_what_they_modulate() {
+        for algorithm in 1..=32 {
+            let dx7 = Algorithm::dx7(algorithm).unwrap();
+            let order = dx7.order(6);
+            let position = |i: usize| order.iter().position(|o| *o == i).unwrap();
+
+            assert_eq!(order.len(), 6);
+            for (m, c) in &dx7.routes {
+                assert!(position(*m) < position(*c), "algorithm {}", algorithm);
+            }
+        }
+
+        assert!(Algorithm::dx7(0).is_none());
+        assert!(Algorithm::dx7(33).is_none());
+    }
+
+    /*
+     * DX7 algorithm 4, two stacks of three with the top of the second one
+     * fed back from its carrier, against the same graph written out by hand
+     */
+    #[test]
+    fn dx7_algorithm_4_routing_and_feedback() {
+        let ratios = [1.0, 2.0, 3.0, 0.5, 1.5, 3.5];
+        let levels = [1.0, 0.8, 0.6, 1.0, 0.9, 0.7];
+        let fm = FM {
+            operators: (0..6)
+                .map(|i| Operator {
+                    frequency: OperatorFrequency::Ratio(ratios[i]),
+                    level: levels[i],
+                    ..Operator::DEFAULT
+                })
+                .collect(),
+            algorithm: Algorithm::dx7(4).unwrap(),
+            modulation: Modulation::Phase,
+            feedback: 1.2,
+        };
+
+        let note = Note::new(Pitch::A4, 4.0, 0.0, Instrument::DEFAULT, 1.0);
+        let mut state = FMState::default();
+        let f = 440.0;
+
+        let mut history = [0.0; 2];
+        for n in 0..2000 {
+            let t = n as f64 / WAV::SAMPLE_RATE as f64;
+            let sine = |i: usize, modulation: f64| {
+                let phase = (t * f * ratios[i] as f64).fract();
+                levels[i] as f64 * (TAU * phase + modulation).sin()
+            };
+
+            let o2 = sine(2, 0.0);
+            let o1 = sine(1, o2);
+            let o0 = sine(0, o1);
+            // Operator 5 hears the carrier 3 of the two previous samples
+            let o5 = sine(5, 1.2 * (history[0] + history[1]) / 2.0);
+            let o4 = sine(4, o5);
+            let o3 = sine(3, o4);
+            history = [o3, history[0]];
+
+            let v = fm.play(t as f32, f, &note, &mut state);
+            assert!((v as f64 - (o0 + o3)).abs() < 1e-5, "sample {}", n);
+        }
+    }
+}
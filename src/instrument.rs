@@ -1,149 +1,624 @@
-use biquad::{Biquad, DirectForm1};
+use std::rc::Rc;
 
-use crate::{pitch::Pitch, roll::Roll};
-use std::f32::consts::PI;
+use crate::{
+    drums::{DrumKit, DrumState},
+    filter::{Filter, FilterState},
+    fm::{FMState, FM},
+    io::{Stereo, WAV},
+    modulation::{Modulations, Modulator, Route},
+    physical::{PhysicalModel, PhysicalState},
+    pitch::Pitch,
+    random::Random,
+    roll::{Roll, Timeline},
+    sampler::{Sampler, SamplerState},
+    tuning::Tuning,
+    wavetable::Wavetable,
+};
 
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
-pub enum Generator {
+pub enum Generator<'a> {
     Sine,
+    // High for `width` of the cycle
     Square,
+    // Rises for `width` of the cycle, a sawtooth at 0 or 1
     Triangle,
     Sawtooth,
     DC,
+    // Flat spectrum
+    WhiteNoise,
+    // -3 dB per octave, for wind and breath
+    PinkNoise,
+    // -6 dB per octave, a random walk
+    BrownNoise,
+    // New random level `rate` times a second, held in between
+    SampleAndHold(f32),
+    // Single-cycle tables, `position` in [0, 1] morphs through the frames
+    Wavetable { table: &'a Wavetable, position: f32 },
+}
+
+// How waveforms with discontinuities are kept from aliasing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Antialiasing {
+    // Ideal waveforms, harmonics above Nyquist fold back
+    None,
+    // Polynomial corrections around every jump (PolyBLEP) and corner (PolyBLAMP)
+    PolyBLEP,
+}
+
+// Where the phase of an oscillator is when its note starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum StartPhase {
+    // As if the oscillator had been running since the song started
+    FreeRunning,
+    // Every note starts at phase 0
+    Reset,
+    // Seeded from the note, so renders stay reproducible
+    Random,
 }
 
 /*********************/
 #[derive(Debug, Clone, Copy)]
-pub struct Oscillator {
-    pub generator: Generator,
+pub struct Oscillator<'a> {
+    pub generator: Generator<'a>,
     pub velocity: f32,
+    pub antialiasing: Antialiasing,
+    pub start_phase: StartPhase,
+    // Shape of squares and triangles in (0, 1), 0.5 for the symmetric ones
+    pub width: f32,
+    // Moves `width` by up to the depth with the modulator (PWM)
+    pub width_modulation: Option<(Modulator<'a>, f32)>,
+    // Coarse and fine tuning, added up
+    pub octave: i8,
+    pub semitones: i8,
+    pub cents: f32,
+    pub unison: Unison,
+}
+
+impl Default for Oscillator<'_> {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
 }
 
-impl Oscillator {
+impl<'a> Oscillator<'a> {
+    // Single full scale sine, free running on the note pitch
+    pub const DEFAULT: Oscillator<'a> = Oscillator {
+        generator: Generator::Sine,
+        velocity: 1.0,
+        antialiasing: Antialiasing::PolyBLEP,
+        start_phase: StartPhase::FreeRunning,
+        width: 0.5,
+        width_modulation: None,
+        octave: 0,
+        semitones: 0,
+        cents: 0.0,
+        unison: Unison::NONE,
+    };
+
+    // Kept off the extremes so squares don't vanish and triangles don't blow up
+    const MIN_WIDTH: f32 = 0.01;
+
+    // Note frequency `f` moved by the coarse and fine tuning
+    pub fn frequency(&self, f: f64) -> f64 {
+        let cents = (self.octave as f64 * 12.0 + self.semitones as f64) * 100.0 + self.cents as f64;
+
+        f * 2f64.powf(cents / 1200.0)
+    }
+
+    // State of every unison voice of a note starting at `t` seconds on frequency `f`
+    pub fn start(&self, t: f64, f: f64, random: &mut Random) -> Vec<OscillatorState> {
+        (0..self.unison.voices.max(1) as usize)
+            .map(|i| {
+                let f = self.frequency(f) * self.unison.ratio(i);
+                let phase = match self.start_phase {
+                    StartPhase::FreeRunning => (t * f).fract(),
+                    StartPhase::Reset => 0.0,
+                    StartPhase::Random => random.next_f64(),
+                };
+
+                OscillatorState::new(phase, Random::new(random.next_u64()))
+            })
+            .collect()
+    }
+
+    // Every unison voice playing note frequency `f`, see Oscillator::sample
+    pub fn play(&self, states: &mut [OscillatorState], f: f64, width: f32) -> Stereo {
+        let f = self.frequency(f);
+        let gain = 1.0 / (states.len().max(1) as f32).sqrt();
+
+        states
+            .iter_mut()
+            .enumerate()
+            .fold(Stereo::default(), |prev, (i, state)| {
+                let v = self.sample(state, f * self.unison.ratio(i), width);
+
+                prev + Stereo::pan(v * gain, self.unison.pan(i))
+            })
+    }
+
+    // Width at `t`, modulation included
+    pub fn width(&self, t: f32, note: &Note) -> f32 {
+        let mut width = self.width;
+
+        if let Some((modulator, depth)) = self.width_modulation {
+            width += modulator.value(t, note) * depth;
+        }
+
+        width
+    }
+
+    /*
+     * One sample at the state phase, which is then advanced by a sample of `f`
+     * so the frequency can change on every sample without jumps
+     */
+    pub fn sample(&self, state: &mut OscillatorState, f: f64, width: f32) -> f32 {
+        let dt = f / WAV::SAMPLE_RATE as f64;
+        let width = width.clamp(Self::MIN_WIDTH, 1.0 - Self::MIN_WIDTH);
+
+        let v = match self.generator {
+            g @ (Generator::WhiteNoise
+            | Generator::PinkNoise
+            | Generator::BrownNoise
+            | Generator::SampleAndHold(_)) => state.noise(g),
+            _ => self.wave(state.phase, dt.abs(), width as f64),
+        };
+
+        state.phase = (state.phase + dt).rem_euclid(1.0);
+
+        v as f32 * self.velocity
+    }
+
     /*
      * Desmos: https://www.desmos.com/calculator/2xswrci3s0
+     * `phase` is in [0, 1) and `dt` is the phase increment of one sample
      */
-    pub fn play(&self, t: f32, f: f32) -> f32 {
-        #[allow(non_snake_case)]
-        let T = 1. / f;
-        let m = t % T; // MOD
-
-        // Generator
-        let v: f32 = match self.generator {
-            Generator::Sine => (2.0 * PI * f * t).sin(),
+    fn wave(&self, phase: f64, dt: f64, width: f64) -> f64 {
+        let band_limited = self.antialiasing == Antialiasing::PolyBLEP;
+        let half = (phase + 0.5).fract();
+
+        match self.generator {
+            Generator::Sine => (2.0 * std::f64::consts::PI * phase).sin(),
             Generator::Square => {
-                if t % T < T / 2.0 {
-                    1.0
-                } else {
-                    -1.0
+                let mut v = if phase < width { 1.0 } else { -1.0 };
+
+                if band_limited {
+                    v += Self::poly_blep(phase, dt)
+                        - Self::poly_blep((phase + 1.0 - width).fract(), dt);
                 }
+
+                v
             }
             Generator::Triangle => {
-                if m < T / 4.0 {
-                    4.0 * f * (t % (T / 2.0))
-                } else if m < 3. * T / 4.0 {
-                    -4.0 * f * m + 2.
+                // Trough at 0 and peak at `width`, crossing zero upwards at phase 0
+                let p = (phase + width / 2.0).fract();
+                let mut v = if p < width {
+                    2.0 * p / width - 1.0
                 } else {
-                    4.0 * f * (t % (T / 2.0)) - 2.0
+                    1.0 - 2.0 * (p - width) / (1.0 - width)
+                };
+
+                if band_limited {
+                    // Half the slope change at each corner, 4 for the symmetric one
+                    let corner = 1.0 / width + 1.0 / (1.0 - width);
+                    v += corner * dt * Self::poly_blamp(p, dt);
+                    v -= corner * dt * Self::poly_blamp((p + 1.0 - width).fract(), dt);
                 }
+
+                v
+            }
+            Generator::Sawtooth => {
+                let mut v = 2.0 * half - 1.0;
+
+                if band_limited {
+                    v -= Self::poly_blep(half, dt);
+                }
+
+                v
             }
-            Generator::Sawtooth => 2.0 * ((t - (T / 2.0)) % T) - 1.0,
             Generator::DC => 1.0,
-        };
+            Generator::Wavetable { table, position } => table.sample(phase, dt, position),
+            // Stateful, see OscillatorState::noise
+            Generator::WhiteNoise
+            | Generator::PinkNoise
+            | Generator::BrownNoise
+            | Generator::SampleAndHold(_) => 0.0,
+        }
+    }
 
-        v * self.velocity
+    // Residual of a band-limited step of 2 at phase 0
+    fn poly_blep(phase: f64, dt: f64) -> f64 {
+        if phase < dt {
+            let t = phase / dt;
+            t + t - t * t - 1.0
+        } else if phase > 1.0 - dt {
+            let t = (phase - 1.0) / dt;
+            t * t + t + t + 1.0
+        } else {
+            0.0
+        }
+    }
+
+    // Residual of a band-limited corner at phase 0, the integral of poly_blep
+    fn poly_blamp(phase: f64, dt: f64) -> f64 {
+        if phase < dt {
+            let t = phase / dt - 1.0;
+            -t * t * t / 3.0
+        } else if phase > 1.0 - dt {
+            let t = (phase - 1.0) / dt + 1.0;
+            t * t * t / 3.0
+        } else {
+            0.0
+        }
     }
 }
 
 /*********************/
+// Copies of an oscillator stacked on the same note, spread in pitch and across the stereo field
+#[derive(Debug, Clone, Copy)]
+pub struct Unison {
+    pub voices: u8,
+    // Cents between the lowest and the highest voice
+    pub detune: f32,
+    // In [0, 1], the outer voices are panned hard left and right at 1
+    pub spread: f32,
+}
+
+impl Unison {
+    pub const NONE: Unison = Unison {
+        voices: 1,
+        detune: 0.0,
+        spread: 0.0,
+    };
 
+    // Where voice `i` sits between the first one (-1) and the last one (1)
+    fn position(&self, i: usize) -> f32 {
+        if self.voices <= 1 {
+            return 0.0;
+        }
+
+        i as f32 / (self.voices - 1) as f32 * 2.0 - 1.0
+    }
+
+    fn ratio(&self, i: usize) -> f64 {
+        2f64.powf((self.position(i) * self.detune / 2.0) as f64 / 1200.0)
+    }
+
+    fn pan(&self, i: usize) -> f32 {
+        self.position(i) * self.spread
+    }
+}
+
+/*********************/
+// Per voice state of an Oscillator
+#[derive(Debug, Clone)]
+pub struct OscillatorState {
+    pub phase: f64,
+    pub random: Random,
+    // Noise filter memory, and the level sample and hold is holding
+    noise: [f64; 7],
+    held: f64,
+    hold_phase: f64,
+}
+
+impl OscillatorState {
+    pub fn new(phase: f64, mut random: Random) -> Self {
+        let held = random.next_f64() * 2.0 - 1.0;
+
+        Self {
+            phase,
+            random,
+            noise: [0.0; 7],
+            held,
+            hold_phase: 0.0,
+        }
+    }
+
+    fn noise(&mut self, generator: Generator) -> f64 {
+        let white = self.random.next_f64() * 2.0 - 1.0;
+        let b = &mut self.noise;
+
+        match generator {
+            Generator::PinkNoise => {
+                // Paul Kellet's refined filter, within 0.05 dB of -3 dB/octave
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.1538520;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+
+                // Peaks rarely pass 0.9, the clamp only guarantees the range
+                (pink * 0.11).clamp(-1.0, 1.0)
+            }
+            Generator::BrownNoise => {
+                // Leaky integrator so the walk doesn't drift away
+                b[0] = (b[0] + 0.02 * white) / 1.02;
+
+                // Peaks about 1 once in a few minutes
+                (b[0] * 3.5).clamp(-1.0, 1.0)
+            }
+            Generator::SampleAndHold(rate) => {
+                self.hold_phase += rate as f64 / WAV::SAMPLE_RATE as f64;
+
+                if self.hold_phase >= 1.0 {
+                    self.hold_phase = self.hold_phase.fract();
+                    self.held = white;
+                }
+
+                self.held
+            }
+            _ => white,
+        }
+    }
+}
+
+/*********************/
+// Shape of the envelope segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Curve {
+    Linear,
+    // Fast at first then slowing down, like analog envelopes
+    Exponential,
+}
+
+impl Curve {
+    // How much of a segment has been covered at `progress` in [0, 1]
+    fn apply(&self, progress: f32) -> f32 {
+        // Steepness of the exponential segments, 5 time constants long
+        const K: f32 = 5.0;
+
+        match self {
+            Curve::Linear => progress,
+            Curve::Exponential => (1.0 - (-K * progress).exp()) / (1.0 - (-K).exp()),
+        }
+    }
+}
+
+/*********************/
+/*
+ * Delay, attack, hold, decay, sustain and release envelope in [0, 1]. The
+ * release starts when the note ends, from whatever level it had reached.
+ */
 #[derive(Debug, Clone, Copy)]
 pub struct Envelope {
+    pub delay_duration: f32,
     pub attack_duration: f32,
+    // Time spent at the peak before decaying
+    pub hold_duration: f32,
     pub decay_duration: f32,
-    pub decay_ratio: f32,
+    pub sustain_level: f32,
     pub release_duration: f32,
+    pub curve: Curve,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
 }
 
 impl Envelope {
-    fn play(&self, t: f32, note: &Note) -> f32 {
-        let rt = t - note.start.seconds();
-        if !self.is_active(t, note) {
+    // Gate, full level from the start to the end of the note
+    pub const DEFAULT: Envelope = Envelope {
+        delay_duration: 0.0,
+        attack_duration: 0.0,
+        hold_duration: 0.0,
+        decay_duration: 0.0,
+        sustain_level: 1.0,
+        release_duration: 0.0,
+        curve: Curve::Linear,
+    };
+
+    pub fn play(&self, t: f32, note: &Note) -> f32 {
+        let rt = t - note.start_time;
+        if !self.is_active(t, note) || rt < 0.0 {
+            return 0.0;
+        }
+
+        let duration = note.duration_time();
+
+        if rt < duration {
+            return self.gate(rt);
+        }
+
+        // Release
+        let progress = Self::progress(rt - duration, self.release_duration);
+        self.gate(duration) * (1.0 - self.curve.apply(progress))
+    }
+
+    // Level `rt` seconds after the start of a note still held
+    fn gate(&self, rt: f32) -> f32 {
+        let mut rt = rt - self.delay_duration;
+
+        if rt < 0.0 {
+            // Delay
             return 0.0;
         }
 
         if rt < self.attack_duration {
             // Attack
-            return rt % self.attack_duration * self.decay_ratio / self.attack_duration;
+            return self.curve.apply(Self::progress(rt, self.attack_duration));
+        }
+        rt -= self.attack_duration;
+
+        if rt < self.hold_duration {
+            // Hold
+            return 1.0;
         }
+        rt -= self.hold_duration;
 
-        if rt < self.attack_duration + self.decay_duration {
+        if rt < self.decay_duration {
             // Decay
-            return self.decay_ratio
-                - (rt - self.attack_duration) % self.decay_duration / (2.0 * self.decay_duration);
+            let progress = self.curve.apply(Self::progress(rt, self.decay_duration));
+            return 1.0 - (1.0 - self.sustain_level) * progress;
         }
 
-        if rt <= self.attack_duration + self.decay_duration + note.duration.seconds() {
-            // Sustain
+        // Sustain
+        self.sustain_level
+    }
+
+    // Share of a segment lasting `duration` covered after `elapsed`
+    fn progress(elapsed: f32, duration: f32) -> f32 {
+        if duration <= 0.0 {
             return 1.0;
         }
 
-        // Release
-        -(rt - self.attack_duration
-            - self.decay_duration
-            - self.release_duration
-            - note.duration.seconds())
-            % self.release_duration
-            / self.release_duration
+        (elapsed / duration).clamp(0.0, 1.0)
     }
 
-    fn is_active(&self, t: f32, note: &Note) -> bool {
-        t < note.start.seconds()
-            + note.duration.seconds()
-            + self.attack_duration
-            + self.decay_duration
-            + self.release_duration
+    pub fn is_active(&self, t: f32, note: &Note) -> bool {
+        t < note.end_time + self.release_duration
     }
 }
 
 /*********************/
 #[derive(Debug, Clone)]
 pub struct Instrument<'a> {
-    pub oscillators: Vec<&'a Oscillator>,
+    pub oscillators: Vec<&'a Oscillator<'a>>,
     pub envelope: Option<&'a Envelope>,
+    // Operators mixed in with the oscillators
+    pub fm: Option<&'a FM<'a>>,
+    // Recorded sounds mixed in with the oscillators
+    pub sampler: Option<&'a Sampler>,
+    // Simulated instrument mixed in with the oscillators
+    pub physical: Option<&'a PhysicalModel>,
+    // Synthesised drums picked by the key of each note
+    pub drums: Option<&'a DrumKit>,
     pub velocity: f32,
-    pub filter: Option<DirectForm1<f32>>,
+    pub filter: Option<&'a Filter<'a>>,
+    // Modulation matrix, every route adds up on its target
+    pub modulations: Vec<Route<'a>>,
+}
+
+impl Default for Instrument<'_> {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
 }
 
 impl<'a> Instrument<'a> {
-    pub fn play(&mut self, t: f32, f: f32, note: &Note) -> f32 {
+    // Plays nothing until given a source
+    pub const DEFAULT: Instrument<'a> = Instrument {
+        oscillators: Vec::new(),
+        envelope: None,
+        fm: None,
+        sampler: None,
+        physical: None,
+        drums: None,
+        velocity: 1.0,
+        filter: None,
+        modulations: Vec::new(),
+    };
+
+    pub fn play(&mut self, t: f32, f: f32, note: &Note, voice: &mut Voice) -> Stereo {
+        if voice.oscillators.len() != self.oscillators.len() {
+            voice.oscillators = self
+                .oscillators
+                .iter()
+                .map(|o| o.start(t as f64, f as f64, &mut voice.random))
+                .collect();
+        }
+
+        let m = Modulations::at(&self.modulations, t, note);
+        let f = f * m.pitch_ratio();
+
         let mut v = self
             .oscillators
             .iter()
-            .fold(0.0, |prev, o| prev + o.play(t, f));
+            .zip(voice.oscillators.iter_mut())
+            .fold(Stereo::default(), |prev, (o, states)| {
+                prev + o.play(states, f as f64, o.width(t, note) + m.pulse_width)
+            });
 
-        v *= match self.envelope {
-            Some(e) => e.play(t, note),
-            None => 1.0,
-        };
+        if let Some(fm) = self.fm {
+            v += Stereo::pan(fm.play(t, f as f64, note, &mut voice.fm), 0.0);
+        }
 
-        if let Some(filter) = &mut self.filter {
-            v = filter.run(v);
+        if let Some(sampler) = self.sampler {
+            v += sampler.play(t, f, note, &mut voice.sampler);
         }
 
-        v * self.velocity
+        if let Some(physical) = self.physical {
+            v += Stereo::pan(physical.play(t, f, note, &mut voice.physical), 0.0);
+        }
+
+        if let Some(drums) = self.drums {
+            v += drums.play(t, note, &mut voice.drums);
+        }
+
+        v = v
+            * m.gain()
+            * match self.envelope {
+                Some(e) => e.play(t, note),
+                None => 1.0,
+            };
+
+        if let Some(filter) = self.filter {
+            v = filter.run(v, t, f, note, &m, &mut voice.filter);
+        }
+
+        v.balance(m.pan) * self.velocity
     }
 
     pub fn is_active(&self, t: f32, note: &Note) -> bool {
+        // Sources that keep sounding past the end of the note on their own
+        let ringing = self.fm.is_some_and(|fm| fm.is_active(t, note))
+            || self
+                .sampler
+                .is_some_and(|sampler| sampler.is_active(t, note, &note.voice.sampler))
+            || self
+                .physical
+                .is_some_and(|physical| physical.is_active(t, note, &note.voice.physical))
+            || self.drums.is_some_and(|drums| drums.is_active(t, note));
+
         if let Some(e) = self.envelope {
-            e.is_active(t, note)
+            e.is_active(t, note) || ringing
         } else {
-            false
+            ringing
+        }
+    }
+}
+
+/*********************/
+// Playback state of one note, created when the note starts sounding
+#[derive(Debug, Clone)]
+pub struct Voice {
+    // States of the unison voices of every oscillator
+    pub oscillators: Vec<Vec<OscillatorState>>,
+    pub fm: FMState,
+    pub sampler: SamplerState,
+    pub physical: PhysicalState,
+    pub drums: DrumState,
+    pub filter: FilterState,
+    pub random: Random,
+}
+
+impl Voice {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            oscillators: vec![],
+            fm: FMState::default(),
+            sampler: SamplerState::default(),
+            physical: PhysicalState::default(),
+            drums: DrumState::default(),
+            filter: FilterState::default(),
+            random: Random::new(seed),
         }
     }
 }
 
+impl Default for Voice {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
 /*********************/
 #[derive(Debug, Clone)]
 pub struct Note<'a> {
@@ -152,6 +627,13 @@ pub struct Note<'a> {
     pub start: Roll,
     pub duration: Roll,
     pub instrument: Instrument<'a>,
+    // Seconds `start` and `start + duration` fall on, see Note::resolve
+    pub start_time: f32,
+    pub end_time: f32,
+    // Tempo map the note was resolved against
+    timeline: Rc<Timeline>,
+    seed: u64,
+    voice: Voice,
 }
 
 impl<'a> Note<'a> {
@@ -162,31 +644,491 @@ impl<'a> Note<'a> {
         instrument: Instrument<'a>,
         velocity: f32,
     ) -> Self {
-        Self {
+        let seed = (pitch.key() as u64) << 32 | start.to_bits() as u64 ^ velocity.to_bits() as u64;
+
+        let mut note = Self {
             pitch,
             velocity,
             start: Roll::new(start),
             duration: Roll::new(duration),
             instrument,
-        }
+            start_time: 0.0,
+            end_time: 0.0,
+            timeline: Rc::new(Timeline::default()),
+            seed,
+            voice: Voice::new(seed),
+        };
+
+        note.resolve(&Timeline::default());
+        note
     }
 
-    pub fn play(&mut self, t: f32) -> f32 {
+    // Noise and random phases are drawn from `seed`, by default derived from the note
+    #[allow(dead_code)]
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.voice = Voice::new(seed);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Places the note in time according to the tempo of `timeline`
+    pub fn resolve(&mut self, timeline: &Timeline) {
+        self.start_time = self.start.seconds(timeline);
+        self.end_time = (self.start + self.duration).seconds(timeline);
+        self.timeline = Rc::new(timeline.clone());
+    }
+
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    pub fn duration_time(&self) -> f32 {
+        self.end_time - self.start_time
+    }
+
+    pub fn play(&mut self, t: f32, tuning: &Tuning) -> Stereo {
         if !self.is_active(t) {
-            return 0.0;
+            return Stereo::default();
         }
 
         // Note still havent started
-        if t < self.start.seconds() {
-            return 0.0;
+        if t < self.start_time {
+            return Stereo::default();
         }
 
-        self.instrument
-            .play(t, self.pitch as u32 as f32, &self.clone())
-            * self.velocity
+        // Drums only pick a pad by key, other keys the tuning leaves unmapped stay silent
+        let f = if self.instrument.drums.is_some() {
+            self.pitch.frequency()
+        } else {
+            match tuning.frequency(self.pitch) {
+                Some(f) => f,
+                None => return Stereo::default(),
+            }
+        };
+
+        let mut voice = std::mem::take(&mut self.voice);
+        let v = self.instrument.play(t, f, &self.clone(), &mut voice);
+        self.voice = voice;
+
+        v * self.velocity
     }
 
     pub fn is_active(&self, t: f32) -> bool {
-        t < (self.start + self.duration).seconds() || self.instrument.is_active(t, self)
+        t < self.end_time || self.instrument.is_active(t, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::TAU;
+
+    use super::*;
+
+    /*
+     * Energy of the DFT bins that aren't harmonics of `f` over the energy of
+     * those that are, in dB. The bins are 10 Hz apart, so every harmonic of a
+     * multiple of 10 Hz and every alias it folds into land on a bin.
+     */
+    fn aliasing(generator: Generator, antialiasing: Antialiasing, f: f64) -> f64 {
+        const N: usize = WAV::SAMPLE_RATE as usize / 10;
+
+        let oscillator = Oscillator {
+            generator,
+            antialiasing,
+            ..Oscillator::DEFAULT
+        };
+        let mut state = OscillatorState::new(0.0, Random::new(0));
+        let samples: Vec<f64> = (0..N)
+            .map(|_| oscillator.sample(&mut state, f, 0.5) as f64)
+            .collect();
+
+        let energy = |k: usize| {
+            let (re, im) = samples
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(re, im), (n, v)| {
+                    let (sin, cos) = (TAU * (k * n % N) as f64 / N as f64).sin_cos();
+                    (re + v * cos, im - v * sin)
+                });
+
+            re * re + im * im
+        };
+
+        // Parseval, halved to the bins between DC and Nyquist
+        let total =
+            (N as f64 * samples.iter().map(|v| v * v).sum::<f64>() - energy(0) - energy(N / 2))
+                / 2.0;
+
+        let fundamental = (f / 10.0).round() as usize;
+        let harmonics: f64 = (1..)
+            .map(|h| h * fundamental)
+            .take_while(|k| *k < N / 2)
+            .map(energy)
+            .sum();
+
+        10.0 * ((total - harmonics) / harmonics).log10()
+    }
+
+    // A4 up to three octaves higher, where the harmonics fold back the most
+    const HIGH_PITCHES: [f64; 4] = [1760.0, 3520.0, 5000.0, 7040.0];
+
+    #[test]
+    fn band_limited_waveforms_keep_aliasing_low() {
+        for f in HIGH_PITCHES {
+            for (generator, threshold) in [
+                (Generator::Sawtooth, -22.0),
+                (Generator::Square, -22.0),
+                (Generator::Triangle, -35.0),
+            ] {
+                let aliasing = aliasing(generator, Antialiasing::PolyBLEP, f);
+
+                assert!(
+                    aliasing < threshold,
+                    "{:?} at {} Hz: {:.1} dB of aliasing",
+                    generator,
+                    f,
+                    aliasing
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn poly_blep_reduces_aliasing() {
+        for f in HIGH_PITCHES {
+            for generator in [Generator::Sawtooth, Generator::Square, Generator::Triangle] {
+                let naive = aliasing(generator, Antialiasing::None, f);
+                let band_limited = aliasing(generator, Antialiasing::PolyBLEP, f);
+
+                assert!(
+                    band_limited < naive - 10.0,
+                    "{:?} at {} Hz: {:.1} dB with PolyBLEP, {:.1} dB without",
+                    generator,
+                    f,
+                    band_limited,
+                    naive
+                );
+            }
+        }
+    }
+
+    /*********************/
+    // Note held from 0 to `end_time` seconds
+    fn held(end_time: f32) -> Note<'static> {
+        let instrument = Instrument::DEFAULT;
+
+        let mut note = Note::new(Pitch::A4, 1.0, 0.0, instrument, 1.0);
+        note.start_time = 0.0;
+        note.end_time = end_time;
+        note
+    }
+
+    fn assert_levels(envelope: &Envelope, note: &Note, levels: &[(f32, f32)]) {
+        for (t, level) in levels {
+            let v = envelope.play(*t, note);
+            assert!(
+                (v - level).abs() < 1e-3,
+                "{} at {} s, expected {}",
+                v,
+                t,
+                level
+            );
+        }
+    }
+
+    const DAHDSR: Envelope = Envelope {
+        delay_duration: 0.1,
+        attack_duration: 0.2,
+        hold_duration: 0.1,
+        decay_duration: 0.2,
+        sustain_level: 0.5,
+        release_duration: 0.3,
+        curve: Curve::Linear,
+    };
+
+    #[test]
+    fn envelope_stage_boundaries() {
+        let note = held(1.0);
+
+        assert_levels(
+            &DAHDSR,
+            &note,
+            &[
+                // Delay
+                (0.0, 0.0),
+                (0.05, 0.0),
+                // Attack
+                (0.1, 0.0),
+                (0.2, 0.5),
+                // Hold
+                (0.3, 1.0),
+                (0.35, 1.0),
+                // Decay
+                (0.4, 1.0),
+                (0.5, 0.75),
+                // Sustain
+                (0.6, 0.5),
+                (0.99, 0.5),
+                // Release
+                (1.0, 0.5),
+                (1.15, 0.25),
+                (1.3, 0.0),
+            ],
+        );
+
+        assert!(DAHDSR.is_active(1.29, &note));
+        assert!(!DAHDSR.is_active(1.3, &note));
+    }
+
+    #[test]
+    fn envelope_releases_from_current_level() {
+        // Released halfway through the attack, at 0.5 rather than the sustain level
+        let note = held(0.2);
+
+        assert_levels(
+            &DAHDSR,
+            &note,
+            &[(0.15, 0.25), (0.2, 0.5), (0.35, 0.25), (0.5, 0.0)],
+        );
+    }
+
+    #[test]
+    fn envelope_zero_length_stages() {
+        let note = held(1.0);
+
+        // A gate: full level for the whole note then straight to silence
+        assert_levels(&Envelope::DEFAULT, &note, &[(0.0, 1.0), (0.999, 1.0)]);
+        assert!(!Envelope::DEFAULT.is_active(1.0, &note));
+        assert_eq!(Envelope::DEFAULT.play(1.0, &note), 0.0);
+
+        // No attack nor decay starts right on the sustain level
+        let envelope = Envelope {
+            sustain_level: 0.6,
+            ..Envelope::DEFAULT
+        };
+        assert_levels(&envelope, &note, &[(0.0, 0.6), (0.5, 0.6)]);
+
+        // Only a decay falls from the peak
+        let envelope = Envelope {
+            decay_duration: 0.5,
+            sustain_level: 0.0,
+            ..Envelope::DEFAULT
+        };
+        assert_levels(&envelope, &note, &[(0.0, 1.0), (0.25, 0.5), (0.5, 0.0)]);
+    }
+
+    #[test]
+    fn exponential_curve_end_points() {
+        assert_eq!(Curve::Exponential.apply(0.0), 0.0);
+        assert!((Curve::Exponential.apply(1.0) - 1.0).abs() < 1e-6);
+        // Fast at first
+        assert!(Curve::Exponential.apply(0.5) > 0.5);
+
+        let envelope = Envelope {
+            curve: Curve::Exponential,
+            ..DAHDSR
+        };
+        let note = held(1.0);
+
+        assert_levels(
+            &envelope,
+            &note,
+            &[
+                (0.1, 0.0),
+                (0.3, 1.0),
+                (0.4, 1.0),
+                (0.6, 0.5),
+                (1.0, 0.5),
+                (1.3, 0.0),
+            ],
+        );
+        assert!(envelope.play(0.2, &note) > 0.5);
+        assert!(envelope.play(1.15, &note) < 0.25);
+    }
+
+    /*********************/
+    const NOISES: [Generator; 4] = [
+        Generator::WhiteNoise,
+        Generator::PinkNoise,
+        Generator::BrownNoise,
+        Generator::SampleAndHold(1000.0),
+    ];
+
+    fn noise(generator: Generator, seed: u64, samples: usize) -> Vec<f32> {
+        let oscillator = Oscillator {
+            generator,
+            ..Oscillator::DEFAULT
+        };
+        let mut state = OscillatorState::new(0.0, Random::new(seed));
+
+        (0..samples)
+            .map(|_| oscillator.sample(&mut state, 440.0, 0.5))
+            .collect()
+    }
+
+    #[test]
+    fn noise_follows_its_seed() {
+        for generator in NOISES {
+            assert_eq!(noise(generator, 7, 1000), noise(generator, 7, 1000));
+            assert_ne!(noise(generator, 7, 1000), noise(generator, 8, 1000));
+        }
+    }
+
+    #[test]
+    fn sample_and_hold_steps_at_its_rate() {
+        let v = noise(
+            Generator::SampleAndHold(1000.0),
+            0,
+            WAV::SAMPLE_RATE as usize,
+        );
+
+        // Lengths of the runs of equal values, the first and last are cut short
+        let mut runs: Vec<usize> = vec![1];
+        for w in v.windows(2) {
+            match w[0] == w[1] {
+                true => *runs.last_mut().unwrap() += 1,
+                false => runs.push(1),
+            }
+        }
+
+        let period = WAV::SAMPLE_RATE as usize / 1000;
+        assert!((999..=1001).contains(&runs.len()), "{} steps", runs.len());
+        for run in &runs[1..runs.len() - 1] {
+            assert!(run.abs_diff(period) <= 1, "held for {} samples", run);
+        }
+    }
+
+    #[test]
+    fn coloured_noise_stays_in_range() {
+        for generator in [Generator::PinkNoise, Generator::BrownNoise] {
+            for seed in 0..4 {
+                let v = noise(generator, seed, WAV::SAMPLE_RATE as usize * 5);
+
+                assert!(v.iter().all(|v| v.abs() <= 1.0), "{:?}", generator);
+                // Not stuck near silence either
+                assert!(v.iter().any(|v| v.abs() > 0.3), "{:?}", generator);
+            }
+        }
+    }
+
+    #[test]
+    fn phase_is_continuous_across_frequency_changes() {
+        let oscillator = Oscillator::DEFAULT;
+        let mut state = OscillatorState::new(0.25, Random::new(0));
+
+        // A jump from 440 Hz to 660 Hz, then a vibrato changing every sample
+        let frequencies: Vec<f64> = (0..4410)
+            .map(|n| match n {
+                0..=999 => 440.0,
+                1000..=1999 => 660.0,
+                _ => 550.0 + 100.0 * (TAU * n as f64 / 441.0).sin(),
+            })
+            .collect();
+
+        let mut expected = 0.25;
+        let mut previous = oscillator.sample(&mut state, frequencies[0], 0.5);
+        expected += frequencies[0] / WAV::SAMPLE_RATE as f64;
+
+        for f in &frequencies[1..] {
+            let v = oscillator.sample(&mut state, *f, 0.5);
+
+            // The sine never moves more than a sample of its fastest frequency
+            let step = TAU * 660.0 / WAV::SAMPLE_RATE as f64;
+            assert!(((v - previous) as f64).abs() <= step + 1e-6);
+            previous = v;
+
+            expected += f / WAV::SAMPLE_RATE as f64;
+        }
+
+        // Phase is the integral of the frequency, with no restart at the changes
+        assert!(
+            (state.phase - expected.fract()).abs() < 1e-9,
+            "{}",
+            state.phase
+        );
+    }
+
+    #[test]
+    fn pulse_width_sets_the_duty_cycle() {
+        // 128 samples per cycle, so the phase lands exactly on the edges
+        let samples = |antialiasing: Antialiasing, width: f32| -> Vec<f32> {
+            let oscillator = Oscillator {
+                generator: Generator::Square,
+                antialiasing,
+                ..Oscillator::DEFAULT
+            };
+            let mut state = OscillatorState::new(0.0, Random::new(0));
+
+            (0..128 * 40)
+                .map(|_| oscillator.sample(&mut state, WAV::SAMPLE_RATE as f64 / 128.0, width))
+                .collect()
+        };
+
+        for width in [0.125, 0.25, 0.5, 0.75] {
+            let high = samples(Antialiasing::None, width)
+                .iter()
+                .filter(|v| **v > 0.0)
+                .count();
+            assert_eq!(high, (width * 128.0 * 40.0) as usize, "width {}", width);
+
+            // Band-limiting moves the edges, not the average level of 2 * width - 1
+            let v = samples(Antialiasing::PolyBLEP, width);
+            let mean = v.iter().sum::<f32>() / v.len() as f32;
+            assert!((mean - (2.0 * width - 1.0)).abs() < 1e-3, "width {}", width);
+        }
+    }
+
+    #[test]
+    fn unison_spreads_symmetrically() {
+        for voices in 2..=7 {
+            let unison = Unison {
+                voices,
+                detune: 30.0,
+                spread: 0.8,
+            };
+            let last = voices as usize - 1;
+
+            // Outer voices half the detune and the whole spread away from the note
+            assert!((unison.ratio(0) - 2f64.powf(-15.0 / 1200.0)).abs() < 1e-12);
+            assert!((unison.ratio(last) - 2f64.powf(15.0 / 1200.0)).abs() < 1e-12);
+            assert_eq!((unison.pan(0), unison.pan(last)), (-0.8, 0.8));
+
+            for i in 0..=last {
+                let mirror = last - i;
+                assert!((unison.ratio(i) * unison.ratio(mirror) - 1.0).abs() < 1e-8);
+                assert!((unison.pan(i) + unison.pan(mirror)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn single_voice_ignores_detune_and_spread() {
+        let plain = Oscillator {
+            generator: Generator::Sawtooth,
+            ..Oscillator::DEFAULT
+        };
+        let single = Oscillator {
+            unison: Unison {
+                voices: 1,
+                detune: 50.0,
+                spread: 1.0,
+            },
+            ..plain
+        };
+
+        let mut random = Random::new(0);
+        let mut states = single.start(0.3, 440.0, &mut random);
+        let mut state = OscillatorState::new(states[0].phase, Random::new(0));
+        assert_eq!(states.len(), 1);
+        assert_eq!(state.phase, (0.3 * 440.0f64).fract());
+
+        // One centered voice at full gain, like a lone oscillator sample
+        for _ in 0..1000 {
+            let expected = Stereo::pan(plain.sample(&mut state, 440.0, 0.5), 0.0);
+            assert_eq!(single.play(&mut states, 440.0, 0.5), expected);
+        }
     }
 }
@@ -1,9 +1,46 @@
-use std::{fs::File, io::Write};
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Write},
+};
 
-use crate::instrument::Note;
+use crate::{instrument::Note, roll::Timeline, track::Track, tuning::Tuning};
 
 pub trait Audio {
-    fn save(filename: &str, notes: &mut Vec<Note>) -> std::io::Result<()>;
+    // Renders every note where it was resolved, in twelve-tone equal temperament
+    fn save(filename: &str, notes: &mut Vec<Note>) -> std::io::Result<()> {
+        Self::save_with(filename, notes, None, &Tuning::default())
+    }
+
+    #[allow(dead_code)]
+    fn save_with_tuning(
+        filename: &str,
+        notes: &mut Vec<Note>,
+        tuning: &Tuning,
+    ) -> std::io::Result<()> {
+        Self::save_with(filename, notes, None, tuning)
+    }
+
+    // Notes are resolved against `timeline` before rendering, None keeps their own (Note::timeline)
+    fn save_with(
+        filename: &str,
+        notes: &mut Vec<Note>,
+        timeline: Option<&Timeline>,
+        tuning: &Tuning,
+    ) -> std::io::Result<()> {
+        let mut tracks = [Track::new(std::mem::take(notes))];
+        let result = Self::save_tracks(filename, &mut tracks, timeline, tuning);
+        *notes = std::mem::take(&mut tracks[0].notes);
+
+        result
+    }
+
+    // Every track is run through its effects, then they're mixed together
+    fn save_tracks(
+        filename: &str,
+        tracks: &mut [Track],
+        timeline: Option<&Timeline>,
+        tuning: &Tuning,
+    ) -> std::io::Result<()>;
 }
 
 pub struct WAV {}
@@ -11,71 +48,246 @@ pub struct WAV {}
 impl WAV {
     pub const SAMPLE_RATE: u32 = 44100;
     pub const BITS_PER_SAMPLE: u16 = 16;
-    pub const NUM_OF_CHANNELS: u16 = 1;
+    pub const NUM_OF_CHANNELS: u16 = 2;
+
+    /*
+     * Reads 8/16/24/32 bit PCM or 32 bit float files, samples are scaled
+     * to [-1, 1] and left interleaved
+     */
+    pub fn load(filename: &str) -> std::io::Result<WAVData> {
+        Self::parse(&std::fs::read(filename)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> std::io::Result<WAVData> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("not a RIFF WAVE file"));
+        }
+
+        let mut format = None;
+        let mut data: Option<&[u8]> = None;
+        let mut offset = 12;
+
+        while offset + 8 <= bytes.len() {
+            let id = &bytes[offset..offset + 4];
+            let length = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+            let start = offset + 8;
+            let end = start
+                .checked_add(length as usize)
+                .filter(|e| *e <= bytes.len())
+                .ok_or_else(|| invalid("truncated chunk"))?;
+
+            let chunk = &bytes[start..end];
+
+            match id {
+                b"fmt " if chunk.len() >= 16 => {
+                    let mut tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+
+                    // WAVE_FORMAT_EXTENSIBLE keeps the real tag in its sub format
+                    if tag == 0xFFFE && chunk.len() >= 26 {
+                        tag = u16::from_le_bytes([chunk[24], chunk[25]]);
+                    }
+
+                    format = Some((
+                        tag,
+                        u16::from_le_bytes([chunk[2], chunk[3]]),
+                        u32::from_le_bytes(chunk[4..8].try_into().unwrap()),
+                        u16::from_le_bytes([chunk[14], chunk[15]]),
+                    ));
+                }
+                b"data" => data = Some(chunk),
+                _ => {}
+            }
+
+            // Chunks are word aligned
+            offset = end + (length as usize & 1);
+        }
+
+        let (tag, channels, sample_rate, bits) =
+            format.ok_or_else(|| invalid("missing fmt chunk"))?;
+        let data = data.ok_or_else(|| invalid("missing data chunk"))?;
+
+        let samples: Vec<f32> = match (tag, bits) {
+            (1, 8) => data.iter().map(|b| (*b as f32 - 128.0) / 128.0).collect(),
+            (1, 16) => data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+                .collect(),
+            (1, 24) => data
+                .chunks_exact(3)
+                .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2147483648.0)
+                .collect(),
+            (1, 32) => data
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0)
+                .collect(),
+            (3, 32) => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            _ => return Err(invalid("unsupported sample format")),
+        };
+
+        Ok(WAVData {
+            sample_rate,
+            channels: channels.max(1),
+            samples,
+        })
+    }
+}
+
+// One frame of a stereo signal
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stereo {
+    pub left: f32,
+    pub right: f32,
+}
+
+impl Stereo {
+    pub fn new(left: f32, right: f32) -> Self {
+        Self { left, right }
+    }
+
+    // `v` placed with an equal power law, `pan` goes from -1 (left) to 1 (right)
+    pub fn pan(v: f32, pan: f32) -> Self {
+        let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+
+        Self::new(v * angle.cos(), v * angle.sin())
+    }
+
+    // Moves the whole frame towards one side, unchanged in the center
+    pub fn balance(self, pan: f32) -> Self {
+        let gains = Self::pan(std::f32::consts::SQRT_2, pan);
+
+        Self::new(self.left * gains.left, self.right * gains.right)
+    }
+}
+
+impl std::ops::Add<Stereo> for Stereo {
+    type Output = Self;
+
+    fn add(self, rhs: Stereo) -> Self::Output {
+        Self::new(self.left + rhs.left, self.right + rhs.right)
+    }
+}
+
+impl std::ops::AddAssign<Stereo> for Stereo {
+    fn add_assign(&mut self, rhs: Stereo) {
+        self.left += rhs.left;
+        self.right += rhs.right;
+    }
+}
+
+impl std::ops::Mul<f32> for Stereo {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Self::new(self.left * rhs, self.right * rhs)
+    }
+}
+
+impl std::ops::DivAssign<f32> for Stereo {
+    fn div_assign(&mut self, rhs: f32) {
+        self.left /= rhs;
+        self.right /= rhs;
+    }
+}
+
+/*********************/
+// Decoded WAV file
+#[derive(Debug, Clone)]
+pub struct WAVData {
+    pub sample_rate: u32,
+    pub channels: u16,
+    // Interleaved, in [-1, 1]
+    pub samples: Vec<f32>,
+}
+
+impl WAVData {
+    // Average of every channel
+    pub fn mono(&self) -> Vec<f32> {
+        self.samples
+            .chunks(self.channels as usize)
+            .map(|c| c.iter().sum::<f32>() / c.len() as f32)
+            .collect()
+    }
 }
 
 impl Audio for WAV {
-    fn save(filename: &str, notes: &mut Vec<Note>) -> std::io::Result<()> {
+    fn save_tracks(
+        filename: &str,
+        tracks: &mut [Track],
+        timeline: Option<&Timeline>,
+        tuning: &Tuning,
+    ) -> std::io::Result<()> {
+        if let Some(timeline) = timeline {
+            tracks
+                .iter_mut()
+                .flat_map(|t| t.notes.iter_mut())
+                .for_each(|n| n.resolve(timeline));
+        }
+
         let mut file = File::create(filename)?;
 
         // RIFF header
-        file.write(b"RIFF")?;
-        file.write(&36u32.to_le_bytes())?;
-        file.write(b"WAVE")?;
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
 
         // "fmt " subchunk
-        file.write(b"fmt ")?;
-        file.write(&16u32.to_le_bytes())?;
-        file.write(&1u16.to_le_bytes())?;
-        file.write(&Self::NUM_OF_CHANNELS.to_le_bytes())?; // Moro ? Stereo ?
-        file.write(&Self::SAMPLE_RATE.to_le_bytes())?;
-        file.write(
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&Self::NUM_OF_CHANNELS.to_le_bytes())?;
+        file.write_all(&Self::SAMPLE_RATE.to_le_bytes())?;
+        file.write_all(
             &(Self::SAMPLE_RATE * Self::NUM_OF_CHANNELS as u32 * Self::BITS_PER_SAMPLE as u32 / 8)
                 .to_le_bytes(),
         )?;
-        file.write(&(Self::NUM_OF_CHANNELS * Self::BITS_PER_SAMPLE / 8).to_le_bytes())?;
-        file.write(&Self::BITS_PER_SAMPLE.to_le_bytes())?;
+        file.write_all(&(Self::NUM_OF_CHANNELS * Self::BITS_PER_SAMPLE / 8).to_le_bytes())?;
+        file.write_all(&Self::BITS_PER_SAMPLE.to_le_bytes())?;
 
         // TODO: Write Data
-        let mut i = 0.0;
-        let mut buffer: Vec<f32> = vec![];
-        let mut max_value = 0.0;
+        let mut i: u64 = 0;
+        let mut buffer: Vec<Stereo> = vec![];
+        let mut max_value: f32 = 0.0;
 
         loop {
-            let t: f32 = i / Self::SAMPLE_RATE as f32;
+            let t = (i as f64 / Self::SAMPLE_RATE as f64) as f32;
 
-            if notes.iter().all(|e| !e.is_active(t)) {
+            if tracks.iter().all(|e| !e.is_active(t)) {
                 break;
             }
 
-            let v = notes.iter_mut().fold(0.0, |prev, n| prev + n.play(t));
+            let v = tracks.iter_mut().fold(Stereo::default(), |prev, track| {
+                prev + track.play(t, tuning)
+            });
 
-            if v.abs() > max_value {
-                max_value = v.abs();
-            }
+            max_value = max_value.max(v.left.abs()).max(v.right.abs());
 
             buffer.push(v);
 
-            i += 1.0;
+            i += 1;
         }
 
         // Normalize all volume
         buffer.iter_mut().for_each(|v| *v /= max_value);
 
         // "data" subchunk
-        file.write(b"data")?;
-        file.write(
+        file.write_all(b"data")?;
+        file.write_all(
             &(buffer.len() as u32 * Self::NUM_OF_CHANNELS as u32 * Self::BITS_PER_SAMPLE as u32
                 / 8)
             .to_le_bytes(),
         )?;
 
-        file.write(
+        file.write_all(
             buffer
                 .iter()
+                .flat_map(|v| [v.left, v.right])
                 .map(|v| v * 2f32.powf(Self::BITS_PER_SAMPLE as f32 - 1.0))
-                .map(|v| (v as i16).to_le_bytes())
-                .flatten()
+                .flat_map(|v| (v as i16).to_le_bytes())
                 .collect::<Vec<_>>()
                 .as_slice(),
         )?;
@@ -83,3 +295,40 @@ impl Audio for WAV {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instrument::{Instrument, Oscillator},
+        pitch::Pitch,
+    };
+
+    #[test]
+    fn save_keeps_the_timeline_notes_were_resolved_against() {
+        let oscillator = Oscillator::DEFAULT;
+        let instrument = Instrument {
+            oscillators: vec![&oscillator],
+            ..Instrument::DEFAULT
+        };
+
+        // A whole note at 130 BPM, like MIDIFile::notes hands them out
+        let mut note = Note::new(Pitch::A4, 16.0, 0.0, instrument, 1.0);
+        note.resolve(&Timeline::new(130.0));
+        let end_time = note.end_time;
+        let mut notes = vec![note];
+
+        let path = std::env::temp_dir().join("save_keeps_the_timeline.wav");
+        let filename = path.to_str().unwrap();
+        WAV::save(filename, &mut notes).unwrap();
+        let data = WAV::load(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+
+        assert!((end_time - 4.0 * 60.0 / 130.0).abs() < 1e-4);
+        assert_eq!(notes[0].end_time, end_time);
+
+        let frames = data.samples.len() / data.channels as usize;
+        let expected = end_time * WAV::SAMPLE_RATE as f32;
+        assert!((frames as f32 - expected).abs() <= 1.0, "{} frames", frames);
+    }
+}
@@ -1,46 +1,67 @@
-use biquad::{Coefficients, DirectForm1, ToHertz, Q_BUTTERWORTH_F32};
+#![allow(clippy::upper_case_acronyms)]
+
+use biquad::Q_BUTTERWORTH_F32;
+use drums::DrumKit;
+use filter::{Filter, FilterType};
 use instrument::{Envelope, Generator, Instrument, Note, Oscillator};
 use midi::MIDIFile;
 use pitch::Pitch;
+use roll::Timeline;
 
 use crate::io::{Audio, WAV};
 
+mod drums;
+mod filter;
+mod fm;
 mod instrument;
 mod io;
 mod midi;
+mod modulation;
+mod physical;
 mod pitch;
+mod random;
 mod roll;
+mod sampler;
+mod sf2;
+mod track;
+mod tuning;
+mod wavetable;
 
 fn main() {
-    println!("{:?}", MIDIFile::parse("untitled.mid").unwrap());
+    let midi = MIDIFile::parse("untitled.mid").unwrap();
 
     let ins = Instrument {
         oscillators: vec![
             &Oscillator {
                 generator: Generator::Sine,
                 velocity: 0.5,
+                ..Oscillator::DEFAULT
             },
             &Oscillator {
                 generator: Generator::Square,
                 velocity: 0.1,
+                ..Oscillator::DEFAULT
             },
             &Oscillator {
                 generator: Generator::Triangle,
                 velocity: 0.25,
+                ..Oscillator::DEFAULT
             },
             &Oscillator {
                 generator: Generator::Sawtooth,
                 velocity: 0.15,
+                ..Oscillator::DEFAULT
             },
         ],
         envelope: Some(&Envelope {
             attack_duration: 0.02,
             decay_duration: 0.05,
-            decay_ratio: 1.5,
+            sustain_level: 0.7,
             release_duration: 0.05,
+            ..Envelope::DEFAULT
         }),
         velocity: 0.7,
-        filter: None,
+        ..Instrument::DEFAULT
     };
 
     let chords_instrument = &Instrument {
@@ -48,36 +69,39 @@ fn main() {
             &Oscillator {
                 generator: Generator::Sine,
                 velocity: 0.5,
+                ..Oscillator::DEFAULT
             },
             &Oscillator {
                 generator: Generator::Square,
                 velocity: 0.3,
+                ..Oscillator::DEFAULT
             },
             &Oscillator {
                 generator: Generator::Triangle,
                 velocity: 0.25,
+                ..Oscillator::DEFAULT
             },
             &Oscillator {
                 generator: Generator::Sawtooth,
                 velocity: 0.13,
+                ..Oscillator::DEFAULT
             },
         ],
         envelope: Some(&Envelope {
             attack_duration: 0.06,
             decay_duration: 0.1,
-            decay_ratio: 1.5,
+            sustain_level: 0.7,
             release_duration: 0.3,
+            ..Envelope::DEFAULT
         }),
         velocity: 0.8,
-        filter: Some(DirectForm1::<f32>::new(
-            Coefficients::<f32>::from_params(
-                biquad::Type::LowPass,
-                (WAV::SAMPLE_RATE as f32).hz(),
-                0.3.khz(),
-                Q_BUTTERWORTH_F32,
-            )
-            .unwrap(),
-        )),
+        filter: Some(&Filter {
+            kind: FilterType::LowPass,
+            cutoff: 300.0,
+            resonance: Q_BUTTERWORTH_F32,
+            ..Filter::DEFAULT
+        }),
+        ..Instrument::DEFAULT
     };
 
     let mut notes: Vec<Note> = vec![];
@@ -122,5 +146,23 @@ fn main() {
         }
     }
 
+    let _ = MIDIFile::from_notes(&notes, &Timeline::default()).save("output.mid");
+
     let _ = WAV::save("output.wav", &mut notes);
+
+    let drum_kit = DrumKit::general_midi();
+    let drums = Instrument {
+        drums: Some(&drum_kit),
+        velocity: 0.8,
+        ..Instrument::DEFAULT
+    };
+
+    // Channel 10 plays General MIDI percussion
+    let mut midi_notes = midi.notes(|_, channel| match channel {
+        9 => Some(drums.clone()),
+        _ => Some(ins.clone()),
+    });
+
+    // Notes come resolved against the tempo map of the file
+    let _ = WAV::save("untitled.wav", &mut midi_notes);
 }
@@ -3,20 +3,94 @@
 */
 
 use std::{
+    collections::HashMap,
     fs::File,
-    io::{Read, Seek},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
 };
 
-// Regular Events type
-#[derive(Debug, Clone)]
-enum MIDIEventType {
-    NoteOff,
-    NoteOn,
+use crate::{
+    instrument::{Instrument, Note},
+    pitch::Pitch,
+    roll::{Roll, TimeSignature, Timeline},
+};
+
+// Regular Events type, with the data of every channel voice message
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum MIDIEventType {
+    NoteOff {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    Aftertouch {
+        channel: u8,
+        key: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    // Centered on 0, from -8192 to 8191
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+    // Meta and SysEx events, their payload lives in the track/file
     Other,
 }
 
+#[allow(dead_code)]
+impl MIDIEventType {
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MIDIEventType::NoteOff { channel, .. }
+            | MIDIEventType::NoteOn { channel, .. }
+            | MIDIEventType::Aftertouch { channel, .. }
+            | MIDIEventType::ControlChange { channel, .. }
+            | MIDIEventType::ProgramChange { channel, .. }
+            | MIDIEventType::ChannelPressure { channel, .. }
+            | MIDIEventType::PitchBend { channel, .. } => Some(channel),
+            MIDIEventType::Other => None,
+        }
+    }
+}
+
+// Control change numbers the renderer cares about
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum MIDIControl {
+    BankSelect = 0,
+    ModulationWheel = 1,
+    Volume = 7,
+    Pan = 10,
+    Sustain = 64,
+}
+
+impl PartialEq<u8> for MIDIControl {
+    fn eq(&self, other: &u8) -> bool {
+        (*self as u8) == *other
+    }
+}
+
 // Regular Events name
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
 enum MIDIEventName {
     VoiceNoteOff = 0x80,
     VoiceNoteOn = 0x90,
@@ -32,14 +106,11 @@ impl PartialEq<u8> for MIDIEventName {
     fn eq(&self, other: &u8) -> bool {
         (*self as u8) == (*other & 0xF0)
     }
-
-    fn ne(&self, other: &u8) -> bool {
-        !self.eq(other)
-    }
 }
 
 // Meta Events Name
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
 enum MIDIMetaEventName {
     MetaSequence = 0x00,
     MetaText = 0x01,
@@ -63,34 +134,32 @@ impl PartialEq<u8> for MIDIMetaEventName {
     fn eq(&self, other: &u8) -> bool {
         (*self as u8) == *other
     }
-
-    fn ne(&self, other: &u8) -> bool {
-        !self.eq(other)
-    }
 }
 
 #[derive(Debug, Clone)]
-struct MIDIEvent {
-    event: MIDIEventType,
-    key: u8,
-    velocity: u8,
-    delta_tick: u32,
+pub struct MIDIEvent {
+    pub event: MIDIEventType,
+    pub delta_tick: u32,
 }
 
 #[derive(Debug, Clone)]
-struct MIDINote {
-    key: u8,
-    velocity: u8,
-    start_time: i32,
-    duration: i32,
+pub struct MIDINote {
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+    pub start_time: u32,
+    pub duration: u32,
+    // Bank select (MSB) and program of the channel when the note started
+    pub bank: u8,
+    pub program: u8,
 }
 
 #[derive(Debug, Clone)]
-struct MIDITrack {
-    name: Option<&'static str>,
-    instrument: Option<&'static str>,
-    events: Vec<MIDIEvent>,
-    notes: Vec<MIDINote>,
+pub struct MIDITrack {
+    pub name: Option<&'static str>,
+    pub instrument: Option<&'static str>,
+    pub events: Vec<MIDIEvent>,
+    pub notes: Vec<MIDINote>,
 }
 
 impl MIDITrack {
@@ -102,216 +171,1467 @@ impl MIDITrack {
             notes: vec![],
         }
     }
+
+    // Every event with its absolute tick instead of its delta
+    #[allow(dead_code)]
+    pub fn timed_events(&self) -> impl Iterator<Item = (u32, MIDIEventType)> + '_ {
+        self.events.iter().scan(0, |wall_time, e| {
+            *wall_time += e.delta_tick;
+            Some((*wall_time, e.event))
+        })
+    }
+
+    /*
+     * Track playing `notes` on `channel`, Roll positions are converted
+     * into `division` ticks per quarter note
+     */
+    #[allow(dead_code)]
+    pub fn from_notes(
+        name: Option<&'static str>,
+        channel: u8,
+        notes: &[Note],
+        division: u16,
+    ) -> Self {
+        let mut track = Self::new();
+        track.name = name;
+
+        let ticks = |r: Roll| Self::roll_to_ticks(r, division);
+
+        // NoteOffs sort before NoteOns on the same tick so repeated keys don't overlap
+        let mut timed: Vec<(u32, u8, MIDIEventType)> = vec![];
+
+        for n in notes {
+            let key = n.pitch.key();
+            let velocity = (n.velocity * 127.0).round().clamp(1.0, 127.0) as u8;
+
+            timed.push((
+                ticks(n.start),
+                1,
+                MIDIEventType::NoteOn {
+                    channel,
+                    key,
+                    velocity,
+                },
+            ));
+            timed.push((
+                ticks(n.start + n.duration),
+                0,
+                MIDIEventType::NoteOff {
+                    channel,
+                    key,
+                    velocity: 64,
+                },
+            ));
+        }
+
+        timed.sort_by_key(|(tick, order, _)| (*tick, *order));
+
+        let mut wall_time = 0;
+        for (tick, _, event) in timed {
+            track.events.push(MIDIEvent {
+                event,
+                delta_tick: tick - wall_time,
+            });
+            wall_time = tick;
+        }
+
+        track.pair_notes();
+
+        track
+    }
+
+    fn roll_to_ticks(r: Roll, division: u16) -> u32 {
+        (r.v * division as f32 / Roll::STEPS_PER_QUARTER)
+            .round()
+            .max(0.0) as u32
+    }
+
+    // Pair every NoteOn with the next NoteOff of the same key and channel
+    fn pair_notes(&mut self) {
+        // Started notes, their duration is set once released
+        let mut pending: HashMap<(u8, u8), Vec<MIDINote>> = HashMap::new();
+        // Bank and program of every channel
+        let mut programs = [(0u8, 0u8); 16];
+        let mut wall_time = 0;
+
+        for e in &self.events {
+            wall_time += e.delta_tick;
+
+            match e.event {
+                MIDIEventType::NoteOn {
+                    channel,
+                    key,
+                    velocity,
+                } => {
+                    let (bank, program) = programs[channel as usize & 0x0F];
+                    pending.entry((channel, key)).or_default().push(MIDINote {
+                        channel,
+                        key,
+                        velocity,
+                        start_time: wall_time,
+                        duration: 0,
+                        bank,
+                        program,
+                    })
+                }
+                MIDIEventType::ControlChange {
+                    channel,
+                    controller,
+                    value,
+                } if MIDIControl::BankSelect == controller => {
+                    programs[channel as usize & 0x0F].0 = value
+                }
+                MIDIEventType::ProgramChange { channel, program } => {
+                    programs[channel as usize & 0x0F].1 = program
+                }
+                MIDIEventType::NoteOff { channel, key, .. } => {
+                    if let Some(stack) = pending.get_mut(&(channel, key)) {
+                        if !stack.is_empty() {
+                            let mut note = stack.remove(0);
+                            note.duration = wall_time - note.start_time;
+                            self.notes.push(note);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Notes never released are held until the end of the track
+        for mut note in pending.into_values().flatten() {
+            note.duration = wall_time - note.start_time;
+            self.notes.push(note);
+        }
+
+        self.notes.sort_by_key(|n| (n.start_time, n.channel, n.key));
+    }
+}
+
+// Tempo change, in microseconds per quarter note from `tick` on
+#[derive(Debug, Clone, Copy)]
+pub struct MIDITempo {
+    pub tick: u32,
+    pub microseconds_per_quarter: u32,
+}
+
+// Time signature change, `denominator` is the actual note value (4 for x/4)
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct MIDITimeSignature {
+    pub tick: u32,
+    pub numerator: u8,
+    pub denominator: u8,
+    pub clocks_per_click: u8,
+    pub thirty_seconds_per_quarter: u8,
+}
+
+#[derive(Debug)]
+pub enum MidiError {
+    Io(std::io::Error),
+    // Header chunk isn't "MThd"
+    BadMagic {
+        offset: u64,
+        found: [u8; 4],
+    },
+    // Input ended in the middle of a chunk
+    TruncatedChunk {
+        offset: u64,
+    },
+    // Variable-length quantity longer than 4 bytes
+    InvalidVariableLength {
+        offset: u64,
+    },
+    // Data byte with no running status to fall back on, or a status we can't decode
+    UnknownStatus {
+        offset: u64,
+        status: u8,
+    },
+    // Zero ticks per quarter note, or an SMPTE division without frames or ticks
+    InvalidDivision {
+        offset: u64,
+        division: u16,
+    },
+    // Events didn't end exactly where the chunk header said they would
+    ChunkLengthMismatch {
+        offset: u64,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl std::fmt::Display for MidiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MidiError::Io(e) => write!(f, "I/O error: {}", e),
+            MidiError::BadMagic { offset, found } => write!(
+                f,
+                "bad chunk magic {:?} at byte {}",
+                String::from_utf8_lossy(found),
+                offset
+            ),
+            MidiError::TruncatedChunk { offset } => {
+                write!(f, "truncated chunk at byte {}", offset)
+            }
+            MidiError::InvalidVariableLength { offset } => {
+                write!(f, "invalid variable-length quantity at byte {}", offset)
+            }
+            MidiError::UnknownStatus { offset, status } => {
+                write!(f, "unknown status {:#X} at byte {}", status, offset)
+            }
+            MidiError::InvalidDivision { offset, division } => {
+                write!(f, "invalid division {:#06X} at byte {}", division, offset)
+            }
+            MidiError::ChunkLengthMismatch {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "chunk at byte {} should be {} bytes long but is {}",
+                offset, expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MidiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MidiError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MidiError {
+    fn from(e: std::io::Error) -> Self {
+        MidiError::Io(e)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct MIDIFile {
     tracks: Vec<MIDITrack>,
+    format: u16,
+    division: u16,
+    tempo_map: Vec<MIDITempo>,
+    time_signatures: Vec<MIDITimeSignature>,
 }
 
 impl MIDIFile {
-    pub fn parse(filename: &str) -> std::io::Result<Self> {
-        let mut file = File::open(filename)?;
+    // Microseconds per quarter note when the file doesn't set a tempo (120 BPM)
+    const DEFAULT_TEMPO: u32 = 500_000;
+    // Ticks per quarter note of the files we write
+    const DEFAULT_DIVISION: u16 = 480;
 
-        let mut instance = Self { tracks: vec![] };
+    pub fn parse(filename: &str) -> Result<Self, MidiError> {
+        Self::from_reader(&mut BufReader::new(File::open(filename)?))
+    }
 
-        let file_id = Self::read_u32(&mut file);
-        let header_length = Self::read_u32(&mut file);
-        let format = Self::read_u16(&mut file);
-        let track_chunks = Self::read_u16(&mut file);
-        let division = Self::read_u16(&mut file);
+    // Empty file with the default tempo, format 0 merges every track when written
+    pub fn new(format: u16, division: u16) -> Self {
+        Self {
+            tracks: vec![],
+            format,
+            division,
+            tempo_map: vec![MIDITempo {
+                tick: 0,
+                microseconds_per_quarter: Self::DEFAULT_TEMPO,
+            }],
+            time_signatures: vec![],
+        }
+    }
 
-        println!(
-            " File id = {}\n Header length = {} \n Format = {} \n Track chunks = {}\n Division = {}",
-            file_id, header_length, format, track_chunks, division
-        );
+    // Single track file of `notes`, with the tempo and time signatures of `timeline`
+    pub fn from_notes(notes: &[Note], timeline: &Timeline) -> Self {
+        let mut instance = Self::new(0, Self::DEFAULT_DIVISION);
+
+        for (r, bpm) in timeline.tempos() {
+            instance.set_tempo(
+                MIDITrack::roll_to_ticks(*r, instance.division),
+                (60_000_000.0 / bpm).round() as u32,
+            );
+        }
 
-        for chunk in 0..track_chunks as usize {
-            println!("--- New Chunk ---");
+        for (r, ts) in timeline.time_signatures() {
+            instance.set_time_signature(
+                MIDITrack::roll_to_ticks(*r, instance.division),
+                ts.numerator,
+                ts.denominator,
+            );
+        }
 
-            let track_id = Self::read_u32(&mut file);
-            let track_length = Self::read_u32(&mut file);
+        instance.push_track(MIDITrack::from_notes(None, 0, notes, instance.division));
 
-            println!("ID = {}, Length = {}", track_id, track_length);
+        instance
+    }
 
-            let mut is_end_of_track = false;
-            let mut wall_time = 0;
-            let mut prev_status: u8 = 0;
+    pub fn push_track(&mut self, track: MIDITrack) {
+        self.tracks.push(track);
+    }
 
-            instance.tracks.push(MIDITrack::new());
+    // Replaces any tempo change already set on `tick`
+    pub fn set_tempo(&mut self, tick: u32, microseconds_per_quarter: u32) {
+        self.tempo_map.retain(|t| t.tick != tick);
+        self.tempo_map.push(MIDITempo {
+            tick,
+            microseconds_per_quarter,
+        });
+        self.tempo_map.sort_by_key(|t| t.tick);
+    }
 
-            while !is_end_of_track && {
-                let rb = file.read(&mut [0u8; 1]);
-                let r = match rb {
-                    Ok(b) => b != 0,
-                    Err(_) => false,
-                };
-                let _ = file.seek(std::io::SeekFrom::Current(-1));
-                r
-            } {
-                let mut status = Self::read_u8(&mut file);
+    // Replaces any time signature already set on `tick`
+    pub fn set_time_signature(&mut self, tick: u32, numerator: u8, denominator: u8) {
+        self.time_signatures.retain(|t| t.tick != tick);
+        self.time_signatures.push(MIDITimeSignature {
+            tick,
+            numerator,
+            denominator,
+            clocks_per_click: 24,
+            thirty_seconds_per_quarter: 8,
+        });
+        self.time_signatures.sort_by_key(|t| t.tick);
+    }
+
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MidiError> {
+        Self::from_reader(&mut Cursor::new(bytes))
+    }
+
+    pub fn from_reader<R: Read + Seek>(file: &mut R) -> Result<Self, MidiError> {
+        let mut instance = Self {
+            tracks: vec![],
+            format: 0,
+            division: 0,
+            tempo_map: vec![],
+            time_signatures: vec![],
+        };
+
+        let header_offset = file.stream_position()?;
+        let file_id = Self::read_magic(file)?;
+        if &file_id != b"MThd" {
+            return Err(MidiError::BadMagic {
+                offset: header_offset,
+                found: file_id,
+            });
+        }
+
+        let header_length = Self::read_u32(file)?;
+        if header_length < 6 {
+            return Err(MidiError::ChunkLengthMismatch {
+                offset: header_offset,
+                expected: 6,
+                actual: header_length as u64,
+            });
+        }
+        let header_end = file.stream_position()? + header_length as u64;
+
+        instance.format = Self::read_u16(file)?;
+        let track_chunks = Self::read_u16(file)?;
+        let division_offset = file.stream_position()?;
+        instance.division = Self::read_u16(file)?;
+
+        // Every time would divide by zero, SMPTE frame rates are negative from -1 to -127
+        let [frames, ticks] = instance.division.to_be_bytes();
+        let valid = match instance.division & 0x8000 {
+            0 => instance.division != 0,
+            _ => frames != 0x80 && ticks != 0,
+        };
+        if !valid {
+            return Err(MidiError::InvalidDivision {
+                offset: division_offset,
+                division: instance.division,
+            });
+        }
 
-                let status_delta_time = Self::read_value(&mut file);
+        // Newer revisions may extend the header, skip what we don't know
+        Self::skip(file, header_end)?;
 
-                println!("Status = {:#X}, Δt = {}", status, status_delta_time);
+        while instance.tracks.len() < track_chunks as usize {
+            let chunk_offset = file.stream_position()?;
+            let track_id = Self::read_magic(file)?;
+            let track_length = Self::read_u32(file)?;
+            let track_end = file.stream_position()? + track_length as u64;
 
-                if status < 0x80 {
-                    status = prev_status;
+            // Alien chunks must be ignored
+            if &track_id != b"MTrk" {
+                Self::skip(file, track_end)?;
+                continue;
+            }
+
+            let track = instance.read_track(file, track_end)?;
+
+            let position = file.stream_position()?;
+            if position != track_end {
+                return Err(MidiError::ChunkLengthMismatch {
+                    offset: chunk_offset,
+                    expected: track_length as u64,
+                    actual: position - (chunk_offset + 8),
+                });
+            }
+
+            instance.tracks.push(track);
+        }
 
-                    let _ = file.seek(std::io::SeekFrom::Current(-1));
+        // Format 1 keeps the maps in the first track but nothing forbids spreading them
+        instance.tempo_map.sort_by_key(|t| t.tick);
+        instance.time_signatures.sort_by_key(|t| t.tick);
+
+        if instance.tempo_map.first().is_none_or(|t| t.tick != 0) {
+            instance.tempo_map.insert(
+                0,
+                MIDITempo {
+                    tick: 0,
+                    microseconds_per_quarter: Self::DEFAULT_TEMPO,
+                },
+            );
+        }
+
+        Ok(instance)
+    }
+
+    fn read_track<R: Read + Seek>(
+        &mut self,
+        file: &mut R,
+        track_end: u64,
+    ) -> Result<MIDITrack, MidiError> {
+        let mut track = MIDITrack::new();
+        let mut is_end_of_track = false;
+        let mut prev_status: u8 = 0;
+        let mut wall_time = 0;
+
+        while !is_end_of_track && file.stream_position()? < track_end {
+            let status_delta_time = Self::read_value(file)?;
+            wall_time += status_delta_time;
+
+            let status_offset = file.stream_position()?;
+            let mut status = Self::read_u8(file)?;
+
+            // Running status: the byte we just read is already data
+            if status < 0x80 {
+                if prev_status == 0 {
+                    return Err(MidiError::UnknownStatus {
+                        offset: status_offset,
+                        status,
+                    });
                 }
 
-                if MIDIEventName::VoiceNoteOff == status {
-                    prev_status = status;
-                    let channel = Self::read_u8(&mut file);
-                    let note = Self::read_u8(&mut file);
-                    let velocity = Self::read_u8(&mut file);
+                status = prev_status;
 
-                    instance.tracks[chunk].events.push(MIDIEvent {
-                        event: MIDIEventType::NoteOff,
-                        key: note,
+                file.seek(SeekFrom::Current(-1))?;
+            }
+
+            let channel = status & 0x0F;
+
+            prev_status = status;
+
+            let event = if MIDIEventName::VoiceNoteOff == status {
+                MIDIEventType::NoteOff {
+                    channel,
+                    key: Self::read_u8(file)?,
+                    velocity: Self::read_u8(file)?,
+                }
+            } else if MIDIEventName::VoiceNoteOn == status {
+                let key = Self::read_u8(file)?;
+                let velocity = Self::read_u8(file)?;
+
+                if velocity == 0 {
+                    MIDIEventType::NoteOff {
+                        channel,
+                        key,
                         velocity,
-                        delta_tick: status_delta_time,
-                    });
-                } else if MIDIEventName::VoiceNoteOn == status {
-                    prev_status = status;
-                    let channel = Self::read_u8(&mut file);
-                    let note = Self::read_u8(&mut file);
-                    let velocity = Self::read_u8(&mut file);
-
-                    instance.tracks[chunk].events.push(MIDIEvent {
-                        event: if velocity == 0 {
-                            MIDIEventType::NoteOff
-                        } else {
-                            MIDIEventType::NoteOn
-                        },
-                        key: note,
+                    }
+                } else {
+                    MIDIEventType::NoteOn {
+                        channel,
+                        key,
                         velocity,
-                        delta_tick: status_delta_time,
-                    });
-                } else if MIDIEventName::VoiceAftertouch == status {
-                    prev_status = status;
-                    let channel = Self::read_u8(&mut file);
-                    let note = Self::read_u8(&mut file);
-                    let velocity = Self::read_u8(&mut file);
-                } else if MIDIEventName::VoiceControlChange == status {
-                    prev_status = status;
-                    let channel = Self::read_u8(&mut file);
-                    let control_id = Self::read_u8(&mut file);
-                    let control_value = Self::read_u8(&mut file);
-                } else if MIDIEventName::VoiceProgramChange == status {
-                    prev_status = status;
-                    let channel = Self::read_u8(&mut file);
-                    let prog_id = Self::read_u8(&mut file);
-                } else if MIDIEventName::VoiceChannelPressure == status {
-                    prev_status = status;
-                    let channel = Self::read_u8(&mut file);
-                    let pressure = Self::read_u8(&mut file);
-                } else if MIDIEventName::VoicePitchBend == status {
-                    prev_status = status;
-                    let channel = Self::read_u8(&mut file);
-                    let nLS7B = Self::read_u8(&mut file);
-                    let nMS7B = Self::read_u8(&mut file);
-                } else if MIDIEventName::SystemExclusive == status {
-                    prev_status = 0;
+                    }
+                }
+            } else if MIDIEventName::VoiceAftertouch == status {
+                MIDIEventType::Aftertouch {
+                    channel,
+                    key: Self::read_u8(file)?,
+                    pressure: Self::read_u8(file)?,
+                }
+            } else if MIDIEventName::VoiceControlChange == status {
+                MIDIEventType::ControlChange {
+                    channel,
+                    controller: Self::read_u8(file)?,
+                    value: Self::read_u8(file)?,
+                }
+            } else if MIDIEventName::VoiceProgramChange == status {
+                MIDIEventType::ProgramChange {
+                    channel,
+                    program: Self::read_u8(file)?,
+                }
+            } else if MIDIEventName::VoiceChannelPressure == status {
+                MIDIEventType::ChannelPressure {
+                    channel,
+                    pressure: Self::read_u8(file)?,
+                }
+            } else if MIDIEventName::VoicePitchBend == status {
+                let lsb = Self::read_u8(file)? as i16;
+                let msb = Self::read_u8(file)? as i16;
 
-                    if status == 0xFF {
-                        let x_type = Self::read_u8(&mut file);
-                        let length = Self::read_value(&mut file);
-
-                        if MIDIMetaEventName::MetaSequence == x_type {
-                            println!(
-                                "Sequence Number: {} {}",
-                                Self::read_u8(&mut file),
-                                Self::read_u8(&mut file)
-                            );
-                        } else if MIDIMetaEventName::MetaText == x_type {
-                            println!("Text: {}", Self::read_string(&mut file, length));
-                        } else if MIDIMetaEventName::MetaCopyright == x_type {
-                            println!("Copyright: {}", Self::read_string(&mut file, length));
-                        } else if MIDIMetaEventName::MetaTrackName == x_type {
-                            instance.tracks[chunk].name =
-                                Some(Self::read_string(&mut file, length));
-                            println!(
-                                "Track Name: {}",
-                                instance.tracks[chunk].name.unwrap_or("Unknown")
-                            );
-                        } else if MIDIMetaEventName::MetaInstrumentName == x_type {
-                            instance.tracks[chunk].instrument =
-                                Some(Self::read_string(&mut file, length));
-                            println!(
-                                "Instrument Name: {}",
-                                instance.tracks[chunk].instrument.unwrap_or("Unknown")
-                            );
-                        } else if MIDIMetaEventName::MetaLyrics == x_type {
-                            println!("Lyrics: {}", Self::read_string(&mut file, length));
-                        } else if MIDIMetaEventName::MetaMarker == x_type {
-                            println!("Marker: {}", Self::read_string(&mut file, length));
-                        } else if MIDIMetaEventName::MetaCuePoint == x_type {
-                            println!("Cue: {}", Self::read_string(&mut file, length));
-                        } else if MIDIMetaEventName::MetaChannelPrefix == x_type {
-                            println!("Prefix: {}", Self::read_string(&mut file, length));
-                        } else if MIDIMetaEventName::MetaEndOfTrack == x_type {
-                            is_end_of_track = true;
-                        }
+                MIDIEventType::PitchBend {
+                    channel,
+                    value: ((msb & 0x7F) << 7 | (lsb & 0x7F)) - 8192,
+                }
+            } else {
+                MIDIEventType::Other
+            };
+
+            // Keep the timing of every event so note pairing stays aligned
+            track.events.push(MIDIEvent {
+                event,
+                delta_tick: status_delta_time,
+            });
+
+            if event == MIDIEventType::Other {
+                prev_status = 0;
+
+                if status == 0xFF {
+                    let x_type = Self::read_u8(file)?;
+                    let length = Self::read_value(file)?;
+                    let data = Self::read_bytes(file, length, track_end)?;
+
+                    if MIDIMetaEventName::MetaTrackName == x_type {
+                        track.name = Some(Self::leak_string(&data));
+                    } else if MIDIMetaEventName::MetaInstrumentName == x_type {
+                        track.instrument = Some(Self::leak_string(&data));
+                    } else if MIDIMetaEventName::MetaEndOfTrack == x_type {
+                        is_end_of_track = true;
+                    } else if MIDIMetaEventName::MetaSetTempo == x_type && data.len() == 3 {
+                        self.tempo_map.push(MIDITempo {
+                            tick: wall_time,
+                            microseconds_per_quarter: u32::from_be_bytes([
+                                0, data[0], data[1], data[2],
+                            ]),
+                        });
+                    } else if MIDIMetaEventName::MetaTimeSignature == x_type && data.len() == 4 {
+                        self.time_signatures.push(MIDITimeSignature {
+                            tick: wall_time,
+                            numerator: data[0],
+                            denominator: 1u8.checked_shl(data[1] as u32).unwrap_or(0),
+                            clocks_per_click: data[2],
+                            thirty_seconds_per_quarter: data[3],
+                        });
                     }
+                } else if status == 0xF0 || status == 0xF7 {
+                    // SysEx payloads carry nothing we play
+                    let length = Self::read_value(file)?;
+                    Self::read_bytes(file, length, track_end)?;
                 } else {
-                    println!("Unrecognised Status Byte: {:#X}", status);
+                    return Err(MidiError::UnknownStatus {
+                        offset: status_offset,
+                        status,
+                    });
                 }
             }
         }
 
-        // file.stre
+        track.pair_notes();
 
-        Ok(instance)
+        Ok(track)
+    }
+
+    #[allow(dead_code)]
+    pub fn tracks(&self) -> &[MIDITrack] {
+        &self.tracks
+    }
+
+    #[allow(dead_code)]
+    pub fn tempo_map(&self) -> &[MIDITempo] {
+        &self.tempo_map
+    }
+
+    #[allow(dead_code)]
+    pub fn time_signatures(&self) -> &[MIDITimeSignature] {
+        &self.time_signatures
+    }
+
+    // Microseconds per quarter note in effect at `tick`
+    #[allow(dead_code)]
+    pub fn tempo_at(&self, tick: u32) -> u32 {
+        self.tempo_map
+            .iter()
+            .take_while(|t| t.tick <= tick)
+            .last()
+            .map_or(Self::DEFAULT_TEMPO, |t| t.microseconds_per_quarter)
+    }
+
+    // Time signature in effect at `tick`, None means the implied 4/4
+    #[allow(dead_code)]
+    pub fn time_signature_at(&self, tick: u32) -> Option<MIDITimeSignature> {
+        self.time_signatures
+            .iter()
+            .take_while(|t| t.tick <= tick)
+            .last()
+            .copied()
     }
 
-    fn read_u8(file: &mut File) -> u8 {
+    /*
+     * Absolute time of `ticks`, integrating every tempo change before it.
+     * SMPTE divisions (negative frames per second, ticks per frame) ignore tempo.
+     */
+    pub fn ticks_to_seconds(&self, ticks: u32) -> f32 {
+        if self.division & 0x8000 != 0 {
+            let fps = -((self.division >> 8) as u8 as i8) as f64;
+            let ticks_per_frame = (self.division & 0xFF) as f64;
+
+            return (ticks as f64 / (fps * ticks_per_frame)) as f32;
+        }
+
+        let division = self.division as f64;
+        let mut seconds = 0.0;
+
+        for (i, t) in self.tempo_map.iter().enumerate() {
+            if t.tick >= ticks {
+                break;
+            }
+
+            let end = match self.tempo_map.get(i + 1) {
+                Some(next) => next.tick.min(ticks),
+                None => ticks,
+            };
+
+            seconds +=
+                (end - t.tick) as f64 * t.microseconds_per_quarter as f64 / 1_000_000.0 / division;
+        }
+
+        seconds as f32
+    }
+
+    /*
+     * Tempo and time signatures as a Timeline, render the notes against it.
+     * SMPTE timed files get a constant 120 BPM the notes are placed on.
+     */
+    pub fn timeline(&self) -> Timeline {
+        let mut timeline = Timeline::new(60_000_000.0 / Self::DEFAULT_TEMPO as f32);
+
+        if self.division & 0x8000 != 0 {
+            return timeline;
+        }
+
+        for t in &self.tempo_map {
+            timeline.set_tempo(
+                self.ticks_to_roll(t.tick, &timeline),
+                60_000_000.0 / t.microseconds_per_quarter.max(1) as f32,
+            );
+        }
+
+        for t in &self.time_signatures {
+            timeline.set_time_signature(
+                self.ticks_to_roll(t.tick, &timeline),
+                TimeSignature {
+                    numerator: t.numerator,
+                    denominator: t.denominator,
+                },
+            );
+        }
+
+        timeline
+    }
+
+    // Position of `ticks` on the Timeline of the file
+    pub fn ticks_to_roll(&self, ticks: u32, timeline: &Timeline) -> Roll {
+        if self.division & 0x8000 != 0 {
+            return Roll::from_seconds(self.ticks_to_seconds(ticks), timeline);
+        }
+
+        Roll::new(ticks as f32 * Roll::STEPS_PER_QUARTER / self.division as f32)
+    }
+
+    /*
+     * Render every paired note of the file, `instrument` picks what plays
+     * each (track index, channel) and can return None to mute it
+     */
+    pub fn notes<'a, F>(&self, mut instrument: F) -> Vec<Note<'a>>
+    where
+        F: FnMut(usize, u8) -> Option<Instrument<'a>>,
+    {
+        self.notes_with(|track, n| instrument(track, n.channel))
+    }
+
+    /*
+     * Render every paired note with what `instrument` picks for the (channel,
+     * bank, program) it was played on, see SoundFont::midi_samplers
+     */
+    #[allow(dead_code)]
+    pub fn program_notes<'a, F>(&self, mut instrument: F) -> Vec<Note<'a>>
+    where
+        F: FnMut(u8, u8, u8) -> Option<Instrument<'a>>,
+    {
+        self.notes_with(|_, n| instrument(n.channel, n.bank, n.program))
+    }
+
+    fn notes_with<'a, F>(&self, mut instrument: F) -> Vec<Note<'a>>
+    where
+        F: FnMut(usize, &MIDINote) -> Option<Instrument<'a>>,
+    {
+        let timeline = self.timeline();
+        let mut notes = vec![];
+
+        for (i, track) in self.tracks.iter().enumerate() {
+            for n in &track.notes {
+                let pitch = match Pitch::from_key(n.key) {
+                    Some(p) => p,
+                    None => continue,
+                };
+
+                if let Some(ins) = instrument(i, n) {
+                    let start = self.ticks_to_roll(n.start_time, &timeline);
+                    let end = self.ticks_to_roll(n.start_time + n.duration, &timeline);
+
+                    let mut note = Note::new(
+                        pitch,
+                        end.v - start.v,
+                        start.v,
+                        ins,
+                        n.velocity as f32 / 127.0,
+                    );
+                    note.resolve(&timeline);
+
+                    notes.push(note);
+                }
+            }
+        }
+
+        notes
+    }
+
+    fn read_exact<R: Read + Seek>(file: &mut R, buf: &mut [u8]) -> Result<(), MidiError> {
+        let offset = file.stream_position()?;
+
+        file.read_exact(buf).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => MidiError::TruncatedChunk { offset },
+            _ => MidiError::Io(e),
+        })
+    }
+
+    fn read_magic<R: Read + Seek>(file: &mut R) -> Result<[u8; 4], MidiError> {
+        let mut magic = [0u8; 4];
+        Self::read_exact(file, &mut magic)?;
+        Ok(magic)
+    }
+
+    fn read_u8<R: Read + Seek>(file: &mut R) -> Result<u8, MidiError> {
         let mut n8 = [0u8; 1];
-        file.read_exact(&mut n8);
-        n8[0]
+        Self::read_exact(file, &mut n8)?;
+        Ok(n8[0])
     }
 
-    fn read_u16(file: &mut File) -> u16 {
+    fn read_u16<R: Read + Seek>(file: &mut R) -> Result<u16, MidiError> {
         let mut n16 = [0u8; 2];
-        file.read_exact(&mut n16);
-        u16::from_be_bytes(n16)
+        Self::read_exact(file, &mut n16)?;
+        Ok(u16::from_be_bytes(n16))
     }
 
-    fn read_u32(file: &mut File) -> u32 {
+    fn read_u32<R: Read + Seek>(file: &mut R) -> Result<u32, MidiError> {
         let mut n32 = [0u8; 4];
-        file.read_exact(&mut n32);
-        u32::from_be_bytes(n32)
+        Self::read_exact(file, &mut n32)?;
+        Ok(u32::from_be_bytes(n32))
     }
 
-    fn read_value(file: &mut File) -> u32 {
+    fn read_value<R: Read + Seek>(file: &mut R) -> Result<u32, MidiError> {
+        let offset = file.stream_position()?;
         let mut value: u32 = 0;
-        let mut buf: u8 = 0;
 
-        loop {
-            buf = Self::read_u8(file);
+        // A quantity is at most 4 bytes (0x0FFFFFFF)
+        for _ in 0..4 {
+            let buf = Self::read_u8(file)?;
 
-            value = ((value & 0x7F) << 7) | (buf & 0x7F) as u32;
+            value = (value << 7) | (buf & 0x7F) as u32;
 
-            if buf & 0x80 != 0 {
-                break;
+            if buf & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(MidiError::InvalidVariableLength { offset })
+    }
+
+    // Payload of a meta or SysEx event, which can't run past its chunk
+    fn read_bytes<R: Read + Seek>(
+        file: &mut R,
+        length: u32,
+        chunk_end: u64,
+    ) -> Result<Vec<u8>, MidiError> {
+        let offset = file.stream_position()?;
+
+        if offset + length as u64 > chunk_end {
+            return Err(MidiError::TruncatedChunk { offset });
+        }
+
+        let mut buf = vec![0u8; length as usize];
+        Self::read_exact(file, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn skip<R: Read + Seek>(file: &mut R, to: u64) -> Result<(), MidiError> {
+        let offset = file.stream_position()?;
+        let end = file.seek(SeekFrom::End(0))?;
+
+        if to > end {
+            return Err(MidiError::TruncatedChunk { offset });
+        }
+
+        file.seek(SeekFrom::Start(to))?;
+        Ok(())
+    }
+
+    fn leak_string(buf: &[u8]) -> &'static str {
+        Box::leak(String::from_utf8_lossy(buf).to_string().into_boxed_str())
+    }
+}
+
+// A message waiting to be written, meta events carry their raw payload
+#[derive(Debug, Clone)]
+enum MIDIWriteEvent {
+    Meta(MIDIMetaEventName, Vec<u8>),
+    Voice(MIDIEventType),
+}
+
+impl MIDIFile {
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(filename)?);
+        self.write(&mut file)?;
+        file.flush()
+    }
+
+    /*
+     * Tempo and time signature changes go in the first track, format 0 merges
+     * every track into that one. Meta and SysEx events we don't keep are dropped.
+     */
+    pub fn write<W: Write>(&self, file: &mut W) -> std::io::Result<()> {
+        let mut conductor: Vec<(u32, MIDIWriteEvent)> = vec![];
+
+        for t in &self.tempo_map {
+            let [_, a, b, c] = t.microseconds_per_quarter.to_be_bytes();
+            conductor.push((
+                t.tick,
+                MIDIWriteEvent::Meta(MIDIMetaEventName::MetaSetTempo, vec![a, b, c]),
+            ));
+        }
+
+        for t in &self.time_signatures {
+            conductor.push((
+                t.tick,
+                MIDIWriteEvent::Meta(
+                    MIDIMetaEventName::MetaTimeSignature,
+                    vec![
+                        t.numerator,
+                        t.denominator.max(1).trailing_zeros() as u8,
+                        t.clocks_per_click,
+                        t.thirty_seconds_per_quarter,
+                    ],
+                ),
+            ));
+        }
+
+        let mut tracks: Vec<Vec<(u32, MIDIWriteEvent)>> = vec![];
+
+        for track in &self.tracks {
+            let mut items = vec![];
+
+            if let Some(name) = track.name {
+                items.push((
+                    0,
+                    MIDIWriteEvent::Meta(MIDIMetaEventName::MetaTrackName, name.into()),
+                ));
+            }
+
+            if let Some(instrument) = track.instrument {
+                items.push((
+                    0,
+                    MIDIWriteEvent::Meta(MIDIMetaEventName::MetaInstrumentName, instrument.into()),
+                ));
+            }
+
+            items.extend(
+                track
+                    .timed_events()
+                    .filter(|(_, e)| *e != MIDIEventType::Other)
+                    .map(|(tick, e)| (tick, MIDIWriteEvent::Voice(e))),
+            );
+
+            if self.format == 0 && !tracks.is_empty() {
+                tracks[0].extend(items);
+            } else {
+                tracks.push(items);
             }
         }
 
-        value
+        if tracks.is_empty() {
+            tracks.push(vec![]);
+        }
+
+        // Conductor events come first on their tick
+        conductor.append(&mut tracks[0]);
+        tracks[0] = conductor;
+
+        file.write_all(b"MThd")?;
+        file.write_all(&6u32.to_be_bytes())?;
+        file.write_all(&self.format.to_be_bytes())?;
+        file.write_all(&(tracks.len() as u16).to_be_bytes())?;
+        file.write_all(&self.division.to_be_bytes())?;
+
+        for mut items in tracks {
+            items.sort_by_key(|(tick, _)| *tick);
+
+            let data = Self::encode_track(&items);
+
+            file.write_all(b"MTrk")?;
+            file.write_all(&(data.len() as u32).to_be_bytes())?;
+            file.write_all(&data)?;
+        }
+
+        Ok(())
     }
 
-    fn read_string(file: &mut File, length: u32) -> &'static str {
-        let mut buf: Vec<u8> = vec![];
+    fn encode_track(items: &[(u32, MIDIWriteEvent)]) -> Vec<u8> {
+        let mut data = vec![];
+        let mut wall_time = 0;
+        let mut prev_status: u8 = 0;
 
-        for _ in 0..length as usize {
-            buf.push(Self::read_u8(file));
+        for (tick, item) in items {
+            Self::write_value(&mut data, tick - wall_time);
+            wall_time = *tick;
+
+            match item {
+                MIDIWriteEvent::Meta(name, payload) => {
+                    // Meta events cancel running status
+                    prev_status = 0;
+
+                    data.push(0xFF);
+                    data.push(*name as u8);
+                    Self::write_value(&mut data, payload.len() as u32);
+                    data.extend_from_slice(payload);
+                }
+                MIDIWriteEvent::Voice(event) => {
+                    let (status, bytes) = Self::encode_event(event);
+
+                    if status != prev_status {
+                        data.push(status);
+                        prev_status = status;
+                    }
+
+                    data.extend(bytes);
+                }
+            }
         }
 
-        Box::leak(
-            String::from_utf8_lossy(buf.as_slice())
-                .to_string()
-                .into_boxed_str(),
-        )
+        data.push(0x00);
+        data.push(0xFF);
+        data.push(MIDIMetaEventName::MetaEndOfTrack as u8);
+        data.push(0x00);
+
+        data
+    }
+
+    fn encode_event(event: &MIDIEventType) -> (u8, Vec<u8>) {
+        match *event {
+            MIDIEventType::NoteOff {
+                channel,
+                key,
+                velocity,
+            } => (
+                MIDIEventName::VoiceNoteOff as u8 | channel,
+                vec![key, velocity],
+            ),
+            MIDIEventType::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => (
+                MIDIEventName::VoiceNoteOn as u8 | channel,
+                vec![key, velocity],
+            ),
+            MIDIEventType::Aftertouch {
+                channel,
+                key,
+                pressure,
+            } => (
+                MIDIEventName::VoiceAftertouch as u8 | channel,
+                vec![key, pressure],
+            ),
+            MIDIEventType::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (
+                MIDIEventName::VoiceControlChange as u8 | channel,
+                vec![controller, value],
+            ),
+            MIDIEventType::ProgramChange { channel, program } => (
+                MIDIEventName::VoiceProgramChange as u8 | channel,
+                vec![program],
+            ),
+            MIDIEventType::ChannelPressure { channel, pressure } => (
+                MIDIEventName::VoiceChannelPressure as u8 | channel,
+                vec![pressure],
+            ),
+            MIDIEventType::PitchBend { channel, value } => {
+                let v = (value + 8192) as u16;
+                (
+                    MIDIEventName::VoicePitchBend as u8 | channel,
+                    vec![(v & 0x7F) as u8, (v >> 7 & 0x7F) as u8],
+                )
+            }
+            MIDIEventType::Other => unreachable!("Other events have no payload to write"),
+        }
+    }
+
+    fn write_value(data: &mut Vec<u8>, value: u32) {
+        let mut bytes = vec![(value & 0x7F) as u8];
+        let mut v = value >> 7;
+
+        while v > 0 {
+            bytes.push((v & 0x7F) as u8 | 0x80);
+            v >>= 7;
+        }
+
+        data.extend(bytes.iter().rev());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(midi: &MIDIFile) -> (Vec<u8>, MIDIFile) {
+        let mut bytes = vec![];
+        midi.write(&mut bytes).unwrap();
+
+        let parsed = MIDIFile::from_bytes(&bytes).unwrap();
+        (bytes, parsed)
+    }
+
+    // Events of `events` at their absolute ticks
+    fn track(name: Option<&'static str>, events: &[(u32, MIDIEventType)]) -> MIDITrack {
+        let mut track = MIDITrack::new();
+        track.name = name;
+
+        let mut wall_time = 0;
+        for (tick, event) in events {
+            track.events.push(MIDIEvent {
+                event: *event,
+                delta_tick: tick - wall_time,
+            });
+            wall_time = *tick;
+        }
+
+        track.pair_notes();
+        track
+    }
+
+    fn voice_events(track: &MIDITrack) -> Vec<(u32, MIDIEventType)> {
+        track
+            .timed_events()
+            .filter(|(_, e)| *e != MIDIEventType::Other)
+            .collect()
+    }
+
+    fn silent() -> Instrument<'static> {
+        Instrument::DEFAULT
+    }
+
+    fn note_on(channel: u8, key: u8) -> MIDIEventType {
+        MIDIEventType::NoteOn {
+            channel,
+            key,
+            velocity: 100,
+        }
+    }
+
+    fn note_off(channel: u8, key: u8) -> MIDIEventType {
+        MIDIEventType::NoteOff {
+            channel,
+            key,
+            velocity: 64,
+        }
+    }
+
+    #[test]
+    fn format_1_keeps_every_track() {
+        let melody = [(0, note_on(0, 60)), (480, note_off(0, 60))];
+        let bass = [
+            (0, note_on(1, 36)),
+            (
+                240,
+                MIDIEventType::PitchBend {
+                    channel: 1,
+                    value: -8192,
+                },
+            ),
+            (
+                480,
+                MIDIEventType::ControlChange {
+                    channel: 1,
+                    controller: 7,
+                    value: 90,
+                },
+            ),
+            (960, note_off(1, 36)),
+        ];
+
+        let mut midi = MIDIFile::new(1, 480);
+        midi.push_track(track(Some("Melody"), &melody));
+        midi.push_track(track(Some("Bass"), &bass));
+
+        let (_, parsed) = round_trip(&midi);
+
+        assert_eq!(parsed.format, 1);
+        assert_eq!(parsed.division, 480);
+        assert_eq!(parsed.tracks().len(), 2);
+        assert_eq!(parsed.tracks()[0].name, Some("Melody"));
+        assert_eq!(parsed.tracks()[1].name, Some("Bass"));
+        assert_eq!(voice_events(&parsed.tracks()[0]), melody);
+        assert_eq!(voice_events(&parsed.tracks()[1]), bass);
+    }
+
+    #[test]
+    fn format_0_merges_tracks() {
+        let mut midi = MIDIFile::new(0, 96);
+        midi.push_track(track(None, &[(0, note_on(0, 60)), (96, note_off(0, 60))]));
+        midi.push_track(track(None, &[(48, note_on(9, 36)), (72, note_off(9, 36))]));
+
+        let (_, parsed) = round_trip(&midi);
+
+        assert_eq!(parsed.format, 0);
+        assert_eq!(parsed.tracks().len(), 1);
+        assert_eq!(
+            voice_events(&parsed.tracks()[0]),
+            [
+                (0, note_on(0, 60)),
+                (48, note_on(9, 36)),
+                (72, note_off(9, 36)),
+                (96, note_off(0, 60)),
+            ]
+        );
+
+        let notes = &parsed.tracks()[0].notes;
+        assert_eq!(notes.len(), 2);
+        assert_eq!((notes[1].channel, notes[1].key), (9, 36));
+        assert_eq!((notes[1].start_time, notes[1].duration), (48, 24));
+    }
+
+    #[test]
+    fn running_status_is_written_and_read() {
+        let events = [
+            (0, note_on(0, 60)),
+            (0, note_on(0, 64)),
+            (0, note_on(0, 67)),
+            (10, note_on(1, 48)),
+        ];
+
+        let mut midi = MIDIFile::new(1, 480);
+        midi.push_track(track(None, &events));
+
+        let (bytes, parsed) = round_trip(&midi);
+
+        // The three chord notes share one status byte, the other channel needs its own
+        assert_eq!(bytes.iter().filter(|b| **b == 0x90).count(), 1);
+        assert_eq!(bytes.iter().filter(|b| **b == 0x91).count(), 1);
+        assert_eq!(voice_events(&parsed.tracks()[0]), events);
+    }
+
+    #[test]
+    fn variable_length_boundaries() {
+        for (value, encoded) in [
+            (0x00, vec![0x00]),
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x81, 0x00]),
+            (0x3FFF, vec![0xFF, 0x7F]),
+            (0x4000, vec![0x81, 0x80, 0x00]),
+            (0x0FFFFFFF, vec![0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let mut data = vec![];
+            MIDIFile::write_value(&mut data, value);
+            assert_eq!(data, encoded, "{:#X}", value);
+
+            let read = MIDIFile::read_value(&mut Cursor::new(&data)).unwrap();
+            assert_eq!(read, value);
+        }
+
+        // The same values as delta times between events
+        let mut tick = 0;
+        let events: Vec<_> = [0x7F, 0x80, 0x3FFF, 0x0FFFFFFF - 0x7F - 0x80 - 0x3FFF]
+            .iter()
+            .enumerate()
+            .map(|(i, delta)| {
+                tick += delta;
+                (tick, note_on(0, 60 + i as u8))
+            })
+            .collect();
+
+        let mut midi = MIDIFile::new(1, 480);
+        midi.push_track(track(None, &events));
+
+        let (_, parsed) = round_trip(&midi);
+        assert_eq!(voice_events(&parsed.tracks()[0]), events);
+    }
+
+    #[test]
+    fn tempo_and_time_signatures_round_trip() {
+        let mut midi = MIDIFile::new(1, 480);
+        midi.set_tempo(0, 461_538);
+        midi.set_tempo(1920, 600_000);
+        midi.set_time_signature(0, 6, 8);
+        midi.set_time_signature(3840, 3, 4);
+        midi.push_track(track(Some("Piano"), &[(0, note_on(0, 60))]));
+
+        let (_, parsed) = round_trip(&midi);
+
+        let tempos: Vec<_> = parsed
+            .tempo_map()
+            .iter()
+            .map(|t| (t.tick, t.microseconds_per_quarter))
+            .collect();
+        assert_eq!(tempos, [(0, 461_538), (1920, 600_000)]);
+
+        let signatures: Vec<_> = parsed
+            .time_signatures()
+            .iter()
+            .map(|t| (t.tick, t.numerator, t.denominator))
+            .collect();
+        assert_eq!(signatures, [(0, 6, 8), (3840, 3, 4)]);
+
+        assert_eq!(parsed.tracks()[0].name, Some("Piano"));
+        assert_eq!(parsed.tempo_at(2000), 600_000);
+    }
+
+    #[test]
+    fn seconds_integrate_tempo_changes() {
+        // 120 BPM, then 60 BPM from the third quarter on at 96 ticks per quarter
+        let midi = MIDIFile::from_bytes(&file(&[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 500 000 µs per quarter
+            0x81, 0x40, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 1 000 000 at tick 192
+            0x00, 0xFF, 0x2F, 0x00,
+        ]))
+        .unwrap();
+
+        assert_eq!(midi.ticks_to_seconds(96), 0.5);
+        assert_eq!(midi.ticks_to_seconds(192), 1.0);
+        assert_eq!(midi.ticks_to_seconds(288), 2.0);
+        assert_eq!(midi.ticks_to_seconds(240), 1.5);
+    }
+
+    #[test]
+    fn smpte_division_ignores_tempo() {
+        let mut bytes = file(&[
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, 0x00, 0xFF, 0x2F, 0x00,
+        ]);
+        // 25 frames per second of 40 ticks
+        bytes[12..14].copy_from_slice(&[0xE7, 40]);
+        let midi = MIDIFile::from_bytes(&bytes).unwrap();
+
+        assert_eq!(midi.ticks_to_seconds(1000), 1.0);
+        assert_eq!(midi.ticks_to_seconds(40), 0.04);
+    }
+
+    #[test]
+    fn from_notes_round_trips() {
+        let instrument = silent();
+        let notes = [
+            Note::new(Pitch::C4, 4.0, 0.0, instrument.clone(), 1.0),
+            Note::new(Pitch::E4, 2.0, 4.0, instrument.clone(), 0.5),
+            Note::new(Pitch::G4, 8.0, 8.0, instrument, 0.8),
+        ];
+
+        let mut timeline = Timeline::new(90.0);
+        timeline.set_time_signature(
+            Roll::new(16.0),
+            TimeSignature {
+                numerator: 3,
+                denominator: 4,
+            },
+        );
+
+        let (_, parsed) = round_trip(&MIDIFile::from_notes(&notes, &timeline));
+
+        assert_eq!(parsed.tempo_at(0), 666_667);
+        assert_eq!(
+            parsed.time_signature_at(480 * 4).map(|t| t.numerator),
+            Some(3)
+        );
+
+        let read = parsed.notes(|_, _| Some(silent()));
+
+        assert_eq!(read.len(), notes.len());
+        for (read, note) in read.iter().zip(&notes) {
+            assert_eq!(read.pitch.key(), note.pitch.key());
+            assert_eq!(read.start.v, note.start.v);
+            assert_eq!(read.duration.v, note.duration.v);
+            assert!((read.velocity - note.velocity).abs() < 1.0 / 127.0);
+        }
+    }
+
+    /*********************/
+    // Format 0 file around a single MTrk chunk of `events`
+    fn file(events: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk".to_vec();
+        bytes.extend((events.len() as u32).to_be_bytes());
+        bytes.extend(events);
+        bytes
+    }
+
+    // Offset of the first track event
+    const EVENTS: u64 = 22;
+
+    fn parse_error(bytes: &[u8]) -> MidiError {
+        match MIDIFile::from_bytes(bytes) {
+            Ok(_) => panic!("{:02X?} parsed", bytes),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = file(&[0x00, 0xFF, 0x2F, 0x00]);
+        bytes[..4].copy_from_slice(b"RIFF");
+
+        assert!(matches!(
+            parse_error(&bytes),
+            MidiError::BadMagic {
+                offset: 0,
+                found: [b'R', b'I', b'F', b'F']
+            }
+        ));
+    }
+
+    #[test]
+    fn truncation_at_every_offset() {
+        let bytes = file(&[
+            0x00, 0xFF, 0x03, 0x04, b'd', b'r', b'u', b'm', // Track name
+            0x00, 0x90, 0x3C, 0x64, // Note on
+            0x60, 0x3C, 0x00, // Running status note off
+            0x00, 0xFF, 0x2F, 0x00, // End of track
+        ]);
+        MIDIFile::from_bytes(&bytes).unwrap();
+
+        // Fields read in one go report where they start, the others the missing byte
+        let fields = [
+            (0, 4),
+            (4, 4),
+            (8, 2),
+            (10, 2),
+            (12, 2),
+            (14, 4),
+            (18, 4),
+            (26, 4),
+        ];
+
+        for n in 0..bytes.len() as u64 {
+            let expected = fields
+                .iter()
+                .find(|(start, length)| (*start..start + length).contains(&n))
+                .map_or(n, |(start, _)| *start);
+
+            match parse_error(&bytes[..n as usize]) {
+                MidiError::TruncatedChunk { offset } => assert_eq!(offset, expected, "at {}", n),
+                e => panic!("at {}: {}", n, e),
+            }
+        }
+    }
+
+    #[test]
+    fn five_byte_variable_length() {
+        let bytes = file(&[0x81, 0x80, 0x80, 0x80, 0x00, 0x90, 0x3C, 0x64]);
+
+        assert!(matches!(
+            parse_error(&bytes),
+            MidiError::InvalidVariableLength { offset: EVENTS }
+        ));
+    }
+
+    #[test]
+    fn data_byte_without_running_status() {
+        let bytes = file(&[0x00, 0x3C, 0x64]);
+        assert!(matches!(
+            parse_error(&bytes),
+            MidiError::UnknownStatus {
+                offset,
+                status: 0x3C
+            } if offset == EVENTS + 1
+        ));
+
+        // Meta events cancel the running status
+        let bytes = file(&[
+            0x00, 0x90, 0x3C, 0x64, 0x00, 0xFF, 0x03, 0x00, 0x10, 0x3C, 0x00,
+        ]);
+        assert!(matches!(
+            parse_error(&bytes),
+            MidiError::UnknownStatus {
+                offset,
+                status: 0x3C
+            } if offset == EVENTS + 9
+        ));
+    }
+
+    #[test]
+    fn undefined_status() {
+        let bytes = file(&[0x00, 0xF4, 0x00, 0xFF, 0x2F, 0x00]);
+
+        assert!(matches!(
+            parse_error(&bytes),
+            MidiError::UnknownStatus {
+                offset,
+                status: 0xF4
+            } if offset == EVENTS + 1
+        ));
+    }
+
+    #[test]
+    fn invalid_division() {
+        // Ticks per quarter, then SMPTE without ticks per frame and without frames
+        for division in [0x0000u16, 0xE700, 0x8028] {
+            let mut bytes = file(&[0x00, 0xFF, 0x2F, 0x00]);
+            bytes[12..14].copy_from_slice(&division.to_be_bytes());
+
+            assert!(
+                matches!(
+                    parse_error(&bytes),
+                    MidiError::InvalidDivision { offset: 12, division: d } if d == division
+                ),
+                "{:#06X}",
+                division
+            );
+        }
+
+        // 25 frames per second of 40 ticks is fine
+        let mut bytes = file(&[0x00, 0xFF, 0x2F, 0x00]);
+        bytes[12..14].copy_from_slice(&[0xE7, 40]);
+        MIDIFile::from_bytes(&bytes).unwrap();
+    }
+
+    #[test]
+    fn chunk_length_mismatch() {
+        // The note on runs one byte past the declared length
+        let mut bytes = file(&[0x00, 0x90, 0x3C, 0x64]);
+        bytes[21] = 3;
+
+        assert!(matches!(
+            parse_error(&bytes),
+            MidiError::ChunkLengthMismatch {
+                offset: 14,
+                expected: 3,
+                actual: 4
+            }
+        ));
+
+        // Header shorter than its three fields
+        let mut bytes = file(&[0x00, 0xFF, 0x2F, 0x00]);
+        bytes[7] = 4;
+
+        assert!(matches!(
+            parse_error(&bytes),
+            MidiError::ChunkLengthMismatch {
+                offset: 0,
+                expected: 6,
+                actual: 4
+            }
+        ));
     }
 }
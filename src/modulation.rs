@@ -0,0 +1,188 @@
+use std::f32::consts::TAU;
+
+use crate::{
+    instrument::{Envelope, Note},
+    random::Random,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum LFOShape {
+    Sine,
+    Triangle,
+    Square,
+    // New random level every cycle
+    SampleAndHold,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum LFORate {
+    // Cycles a second, whatever the tempo
+    Hertz(f32),
+    // Roll steps a cycle lasts, following the tempo of the Timeline
+    Steps(f32),
+}
+
+/*********************/
+// Low frequency oscillator, starts with the note on a rising zero crossing
+#[derive(Debug, Clone, Copy)]
+pub struct LFO {
+    pub shape: LFOShape,
+    pub rate: LFORate,
+}
+
+impl LFO {
+    // In [-1, 1]
+    pub fn value(&self, t: f32, note: &Note) -> f32 {
+        let cycles = match self.rate {
+            LFORate::Hertz(rate) => (t - note.start_time).max(0.0) * rate,
+            LFORate::Steps(steps) => {
+                (note.timeline().roll(t).v - note.start.v).max(0.0) / steps.max(f32::EPSILON)
+            }
+        };
+        let phase = cycles.fract();
+
+        match self.shape {
+            LFOShape::Sine => (TAU * phase).sin(),
+            LFOShape::Triangle => 4.0 * ((phase + 0.75).fract() - 0.5).abs() - 1.0,
+            LFOShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LFOShape::SampleAndHold => {
+                // Drawn from the note seed so renders stay reproducible
+                let cycle = (cycles as u64).wrapping_mul(0x9E3779B97F4A7C15);
+                Random::new(note.seed() ^ cycle).next_f64() as f32 * 2.0 - 1.0
+            }
+        }
+    }
+}
+
+/*********************/
+// Anything a parameter can follow over the life of a note
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum Modulator<'a> {
+    LFO(&'a LFO),
+    Envelope(&'a Envelope),
+}
+
+impl Modulator<'_> {
+    pub fn value(&self, t: f32, note: &Note) -> f32 {
+        match self {
+            Modulator::LFO(lfo) => lfo.value(t, note),
+            Modulator::Envelope(e) => e.play(t, note),
+        }
+    }
+}
+
+/*********************/
+// Parameters of an instrument a modulator can move
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Target {
+    // Semitones, on every oscillator and operator
+    Pitch,
+    // Gain offset from 1, never below silence
+    Level,
+    // Added to the width of every oscillator
+    PulseWidth,
+    // Octaves on the cutoff of the instrument filter, see Filter::cutoff
+    Cutoff,
+    // Added to the instrument filter Q, see Filter::resonance
+    Resonance,
+    // From -1 (left) to 1 (right)
+    Pan,
+}
+
+// One connection of the modulation matrix, the source value is scaled by `depth`
+#[derive(Debug, Clone, Copy)]
+pub struct Route<'a> {
+    pub source: Modulator<'a>,
+    pub target: Target,
+    pub depth: f32,
+}
+
+// Sum of every route on each target, at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Modulations {
+    pub pitch: f32,
+    pub level: f32,
+    pub pulse_width: f32,
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub pan: f32,
+}
+
+impl Modulations {
+    pub fn at(routes: &[Route], t: f32, note: &Note) -> Self {
+        let mut m = Self::default();
+
+        for route in routes {
+            let v = route.source.value(t, note) * route.depth;
+
+            match route.target {
+                Target::Pitch => m.pitch += v,
+                Target::Level => m.level += v,
+                Target::PulseWidth => m.pulse_width += v,
+                Target::Cutoff => m.cutoff += v,
+                Target::Resonance => m.resonance += v,
+                Target::Pan => m.pan += v,
+            }
+        }
+
+        m
+    }
+
+    // Factor on the note frequency
+    pub fn pitch_ratio(&self) -> f32 {
+        2f32.powf(self.pitch / 12.0)
+    }
+
+    pub fn gain(&self) -> f32 {
+        (1.0 + self.level).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::Filter, instrument::Instrument, pitch::Pitch};
+
+    #[test]
+    fn cutoff_and_resonance_routes_reach_the_filter() {
+        let instrument = Instrument::DEFAULT;
+        let note = Note::new(Pitch::C4, 4.0, 0.0, instrument, 1.0);
+
+        // Full level for as long as the note is held
+        let held = Envelope::DEFAULT;
+        let routes = [
+            Route {
+                source: Modulator::Envelope(&held),
+                target: Target::Cutoff,
+                depth: 1.0,
+            },
+            Route {
+                source: Modulator::Envelope(&held),
+                target: Target::Resonance,
+                depth: 2.0,
+            },
+        ];
+
+        let m = Modulations::at(&routes, 0.1, &note);
+        let filter = Filter::DEFAULT;
+        let f = Pitch::C4.frequency();
+
+        assert!((filter.cutoff(0.1, f, &note, &m) - 2.0 * filter.cutoff).abs() < 1e-2);
+        assert!((filter.resonance(&m) - (filter.resonance + 2.0)).abs() < 1e-6);
+
+        // Nothing moves without routes
+        let m = Modulations::at(&[], 0.1, &note);
+        assert!((filter.cutoff(0.1, f, &note, &m) - filter.cutoff).abs() < 1e-2);
+        assert_eq!(filter.resonance(&m), filter.resonance);
+    }
+}
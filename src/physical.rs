@@ -0,0 +1,351 @@
+use std::f64::consts::TAU;
+
+use crate::{instrument::Note, io::WAV, random::Random};
+
+// Instruments simulated from how they make their sound rather than from a waveform
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum PhysicalModel {
+    // Guitars, harps and plucked basses
+    PluckedString(KarplusStrong),
+}
+
+impl PhysicalModel {
+    pub fn play(&self, t: f32, f: f32, note: &Note, state: &mut PhysicalState) -> f32 {
+        match self {
+            PhysicalModel::PluckedString(string) => string.play(t, f, note, state),
+        }
+    }
+
+    // Sounding until the vibration dies out, or for the note if it was never plucked
+    pub fn is_active(&self, t: f32, note: &Note, state: &PhysicalState) -> bool {
+        match &state.string {
+            Some(string) => string.level > SILENCE,
+            None => t < note.end_time,
+        }
+    }
+}
+
+// Level under which a model stops sounding, -80 dB
+const SILENCE: f64 = 1e-4;
+
+/*********************/
+/*
+ * Extended Karplus-Strong plucked string (Jaffe and Smith, 1983): a burst of
+ * noise shaped like the pluck circulates in a delay line one period long,
+ * losing its high frequencies a little more on every round trip
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct KarplusStrong {
+    // Share of the string length from the bridge, 0.5 mutes the even harmonics and 0 none
+    pub pluck_position: f32,
+    // In [0, 1], how much of the noise high end the pluck keeps at full velocity
+    pub brightness: f32,
+    // In [0, 1), extra high frequency loss on every round trip
+    pub damping: f32,
+    // In (0, 1), the decay is shortest at 0.5 and stretches towards the ends
+    pub stretch: f32,
+    // Seconds the fundamental takes to fall by 60 dB, only ever shorter than the string rings on its own
+    pub decay: Option<f32>,
+    // Same once the note ends and the string is muted
+    pub release: f32,
+}
+
+impl Default for KarplusStrong {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl KarplusStrong {
+    // Bright guitar string plucked near the bridge, ringing until released
+    pub const DEFAULT: KarplusStrong = KarplusStrong {
+        pluck_position: 0.15,
+        brightness: 0.8,
+        damping: 0.1,
+        stretch: 0.5,
+        decay: None,
+        release: 0.1,
+    };
+
+    pub fn play(&self, t: f32, f: f32, note: &Note, state: &mut PhysicalState) -> f32 {
+        let string = state
+            .string
+            .get_or_insert_with(|| self.pluck(f as f64, note));
+
+        if f as f64 != string.f {
+            self.tune(string, f as f64);
+        }
+
+        let excitation = string.excitation.get(string.n).copied().unwrap_or(0.0);
+        string.n += 1;
+
+        let size = string.delay.len();
+        let delayed = string.delay[(string.write + size - string.length) % size];
+
+        // Decay stretching two point average, then the damping low pass
+        let stretch = self.stretch.clamp(0.01, 0.99) as f64;
+        let averaged = (1.0 - stretch) * delayed + stretch * string.previous;
+        string.previous = delayed;
+
+        let damping = self.damping.clamp(0.0, 0.99) as f64;
+        string.lowpass = (1.0 - damping) * averaged + damping * string.lowpass;
+
+        // First order allpass for the fraction of a sample the delay line can't hold
+        let (x1, y1) = string.allpass;
+        let tuned = string.c * string.lowpass + x1 - string.c * y1;
+        string.allpass = (string.lowpass, tuned);
+
+        let loss = if t < note.end_time {
+            string.loss
+        } else {
+            string.release_loss
+        };
+        let v = excitation + loss * tuned;
+
+        string.delay[string.write] = v;
+        string.write = (string.write + 1) % size;
+
+        // Peak follower, slow enough to hold over the lowest periods
+        string.level = v.abs().max(string.level * 0.9995);
+
+        v as f32
+    }
+
+    // The string at rest, with the pluck waiting to be fed in
+    fn pluck(&self, f: f64, note: &Note) -> StringState {
+        let period = WAV::SAMPLE_RATE as f64 / f.max(1.0);
+        let samples = period.round().max(1.0) as usize;
+        let mut random = Random::new(note.seed());
+
+        // Softer plucks are duller
+        let pole = (1.0 - self.brightness as f64 * note.velocity as f64).clamp(0.0, 0.99);
+        let mut lowpass = 0.0;
+        let mut noise: Vec<f64> = (0..samples)
+            .map(|_| {
+                lowpass = (1.0 - pole) * (random.next_f64() * 2.0 - 1.0) + pole * lowpass;
+                lowpass
+            })
+            .collect();
+
+        let mean = noise.iter().sum::<f64>() / samples as f64;
+        noise.iter_mut().for_each(|v| *v -= mean);
+
+        // Comb notching the harmonics with a node at the pluck position
+        let offset = (self.pluck_position.clamp(0.0, 1.0) as f64 * period).round() as usize;
+        let mut excitation: Vec<f64> = (0..samples)
+            .map(|i| match i.checked_sub(offset) {
+                Some(j) if offset > 0 => noise[i] - noise[j],
+                _ => noise[i],
+            })
+            .collect();
+
+        let peak = excitation.iter().fold(0.0f64, |peak, v| peak.max(v.abs()));
+        if peak > 0.0 {
+            excitation.iter_mut().for_each(|v| *v /= peak);
+        }
+
+        let mut string = StringState {
+            // Room for bending down an octave
+            delay: vec![0.0; samples * 2 + 4],
+            write: 0,
+            excitation,
+            n: 0,
+            f: 0.0,
+            length: 1,
+            c: 0.0,
+            loss: 1.0,
+            release_loss: 1.0,
+            previous: 0.0,
+            lowpass: 0.0,
+            allpass: (0.0, 0.0),
+            level: 1.0,
+        };
+
+        self.tune(&mut string, f);
+        string
+    }
+
+    /*
+     * Splits the period at `f` between the delay line, the phase delay of the
+     * loop filters and the allpass, and sets the losses giving the decay times
+     */
+    fn tune(&self, string: &mut StringState, f: f64) {
+        let sample_rate = WAV::SAMPLE_RATE as f64;
+        let w = TAU * f.clamp(1.0, sample_rate * 0.49) / sample_rate;
+        let stretch = self.stretch.clamp(0.01, 0.99) as f64;
+        let damping = self.damping.clamp(0.0, 0.99) as f64;
+
+        let filters_delay = (stretch * w.sin()).atan2(1.0 - stretch + stretch * w.cos()) / w
+            + (damping * w.sin()).atan2(1.0 - damping * w.cos()) / w;
+
+        // Keeps the allpass fraction in [0.1, 1.1), where it's flattest
+        let delay = sample_rate / f.max(1.0) - filters_delay;
+        let length = ((delay - 0.1).floor().max(1.0) as usize).min(string.delay.len() - 1);
+        let fraction = (delay - length as f64).max(0.0);
+
+        string.f = f;
+        string.length = length;
+        // Allpass with exactly that phase delay at `f`
+        string.c = ((1.0 - fraction) * w / 2.0).sin() / ((1.0 + fraction) * w / 2.0).sin();
+
+        let filters_gain =
+            ((1.0 - stretch).powi(2) + stretch.powi(2) + 2.0 * stretch * (1.0 - stretch) * w.cos())
+                .sqrt()
+                * (1.0 - damping)
+                / (1.0 - 2.0 * damping * w.cos() + damping * damping).sqrt();
+
+        // Gain of one round trip for a 60 dB fall over `seconds`
+        let loss = |seconds: f32| {
+            let round_trip = 10f64.powf(-3.0 / (seconds.max(1e-3) as f64 * f.max(1.0)));
+            (round_trip / filters_gain).min(1.0)
+        };
+
+        string.loss = self.decay.map_or(1.0, loss);
+        string.release_loss = string.loss.min(loss(self.release));
+    }
+}
+
+/*********************/
+// Per voice state of a PhysicalModel
+#[derive(Debug, Clone, Default)]
+pub struct PhysicalState {
+    string: Option<StringState>,
+}
+
+#[derive(Debug, Clone)]
+struct StringState {
+    // Circular, the string is `length` samples of it
+    delay: Vec<f64>,
+    write: usize,
+    // Fed in over the first period
+    excitation: Vec<f64>,
+    n: usize,
+    // Frequency the string is tuned to, allpass coefficient and round trip gains
+    f: f64,
+    length: usize,
+    c: f64,
+    loss: f64,
+    release_loss: f64,
+    // Filter memories
+    previous: f64,
+    lowpass: f64,
+    allpass: (f64, f64),
+    level: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instrument::Instrument,
+        pitch::Pitch,
+        tuning::{KeyboardMapping, Scale, Tuning},
+    };
+
+    #[test]
+    fn unplucked_string_stops_with_its_note() {
+        let model = PhysicalModel::PluckedString(KarplusStrong::DEFAULT);
+        let instrument = Instrument {
+            physical: Some(&model),
+            ..Instrument::DEFAULT
+        };
+
+        // Only A is mapped, so the string on A#4 is never plucked
+        let mut map = vec![None; 12];
+        map[9] = Some(9);
+        let mapping = KeyboardMapping {
+            map,
+            ..KeyboardMapping::linear()
+        };
+        let tuning = Tuning::new(Scale::equal(12).unwrap(), mapping).unwrap();
+
+        let mut note = Note::new(Pitch::A4S, 4.0, 0.0, instrument, 1.0);
+        let mut n = 0;
+        while note.is_active(n as f32 / WAV::SAMPLE_RATE as f32) {
+            assert_eq!(
+                note.play(n as f32 / WAV::SAMPLE_RATE as f32, &tuning).left,
+                0.0
+            );
+            n += 1;
+        }
+
+        assert!(n as f32 / WAV::SAMPLE_RATE as f32 <= note.end_time + 1e-3);
+    }
+
+    // A4 held for far longer than the tests listen, through KarplusStrong::play alone
+    fn render(string: &KarplusStrong, seconds: f32) -> Vec<f32> {
+        let note = Note::new(Pitch::A4, 256.0, 0.0, Instrument::DEFAULT, 1.0);
+        let mut state = PhysicalState::default();
+
+        (0..(seconds * WAV::SAMPLE_RATE as f32) as usize)
+            .map(|n| {
+                let t = n as f32 / WAV::SAMPLE_RATE as f32;
+                string.play(t, Pitch::A4.frequency(), &note, &mut state)
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|v| v * v).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn string_rings_at_its_pitch() {
+        let samples = render(&KarplusStrong::DEFAULT, 0.5);
+        // Past the pluck, once the loop filters have settled
+        let window = &samples[4410..4410 + 8192];
+
+        let correlation = |lag: usize| -> f64 {
+            window[..window.len() - lag]
+                .iter()
+                .zip(&window[lag..])
+                .map(|(a, b)| *a as f64 * *b as f64)
+                .sum()
+        };
+
+        // Strongest lag around the period, refined between samples by a parabola
+        let lag = (80..125).max_by(|a, b| correlation(*a).total_cmp(&correlation(*b)));
+        let lag = lag.unwrap();
+        let (before, at, after) = (correlation(lag - 1), correlation(lag), correlation(lag + 1));
+        let period = lag as f64 + 0.5 * (before - after) / (before - 2.0 * at + after);
+
+        let f = WAV::SAMPLE_RATE as f64 / period;
+        let cents = 1200.0 * (f / 440.0).log2();
+        assert!(cents.abs() < 5.0, "{} Hz, {} cents off", f, cents);
+    }
+
+    #[test]
+    fn longer_decay_rings_longer() {
+        let short = render(
+            &KarplusStrong {
+                decay: Some(0.5),
+                ..KarplusStrong::DEFAULT
+            },
+            1.2,
+        );
+        let long = render(
+            &KarplusStrong {
+                decay: Some(2.0),
+                ..KarplusStrong::DEFAULT
+            },
+            1.2,
+        );
+
+        // Same pluck, so both start out alike
+        let start = 441..2205;
+        let (short_start, long_start) = (rms(&short[start.clone()]), rms(&long[start]));
+        assert!((short_start / long_start - 1.0).abs() < 0.5);
+
+        // A second in, the short decay is tens of dB further down
+        let tail = 44100..52920;
+        let (short_tail, long_tail) = (rms(&short[tail.clone()]), rms(&long[tail]));
+        assert!(
+            long_tail > 10.0 * short_tail,
+            "{} against {}",
+            long_tail,
+            short_tail
+        );
+        assert!(long_tail < long_start);
+    }
+}
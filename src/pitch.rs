@@ -1,109 +1,355 @@
-#[derive(Debug, Clone, Copy)]
+// A key of the MIDI keyboard, 60 being middle C (C4) and 69 the A4 reference
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pitch(u8);
+
 #[allow(dead_code)]
-pub enum Pitch {
-    C0 = 16,
-    C0S = 17,
-    D0 = 18,
-    D0S = 19,
-    E0 = 21,
-    F0 = 22,
-    F0S = 23,
-    G0 = 25,
-    G0S = 26,
-    A0 = 28,
-    A0S = 29,
-    B0 = 31,
-    C1 = 33,
-    C1S = 35,
-    D1 = 37,
-    D1S = 39,
-    E1 = 41,
-    F1 = 44,
-    F1S = 46,
-    G1 = 49,
-    G1S = 52,
-    A1 = 55,
-    A1S = 58,
-    B1 = 62,
-    C2 = 65,
-    C2S = 69,
-    D2 = 73,
-    D2S = 78,
-    E2 = 82,
-    F2 = 87,
-    F2S = 93,
-    G2 = 98,
-    G2S = 104,
-    A2 = 110,
-    A2S = 117,
-    B2 = 123,
-    C3 = 131,
-    C3S = 139,
-    D3 = 147,
-    D3S = 156,
-    E3 = 165,
-    F3 = 175,
-    F3S = 185,
-    G3 = 196,
-    G3S = 208,
-    A3 = 220,
-    A3S = 233,
-    B3 = 247,
-    C4 = 262,
-    C4S = 277,
-    D4 = 294,
-    D4S = 311,
-    E4 = 330,
-    F4 = 349,
-    F4S = 370,
-    G4 = 392,
-    G4S = 412,
-    A4 = 440,
-    A4S = 466,
-    B4 = 494,
-    C5 = 523,
-    C5S = 554,
-    D5 = 587,
-    D5S = 622,
-    E5 = 659,
-    F5 = 698,
-    F5S = 740,
-    G5 = 784,
-    G5S = 830,
-    A5 = 880,
-    A5S = 932,
-    B5 = 988,
-    C6 = 1047,
-    C6S = 1109,
-    D6 = 1175,
-    D6S = 1245,
-    E6 = 1318,
-    F6 = 1397,
-    F6S = 1480,
-    G6 = 1568,
-    G6S = 1661,
-    A6 = 1760,
-    A6S = 1865,
-    B6 = 1976,
-    C7 = 2093,
-    C7S = 2217,
-    D7 = 2349,
-    D7S = 2489,
-    E7 = 2637,
-    F7 = 2794,
-    F7S = 2960,
-    A7 = 3520,
-    B7 = 3951,
-    C8 = 4186,
-    C8S = 4435,
-    D8 = 4699,
-    D8S = 4978,
-    E8 = 5274,
-    F8 = 5588,
-    F8S = 5920,
-    G8 = 6272,
-    G8S = 6645,
-    A8 = 7040,
-    A8S = 7459,
-    B8 = 7902,
+impl Pitch {
+    pub const C0: Pitch = Pitch(12);
+    pub const C0S: Pitch = Pitch(13);
+    pub const D0: Pitch = Pitch(14);
+    pub const D0S: Pitch = Pitch(15);
+    pub const E0: Pitch = Pitch(16);
+    pub const F0: Pitch = Pitch(17);
+    pub const F0S: Pitch = Pitch(18);
+    pub const G0: Pitch = Pitch(19);
+    pub const G0S: Pitch = Pitch(20);
+    pub const A0: Pitch = Pitch(21);
+    pub const A0S: Pitch = Pitch(22);
+    pub const B0: Pitch = Pitch(23);
+    pub const C1: Pitch = Pitch(24);
+    pub const C1S: Pitch = Pitch(25);
+    pub const D1: Pitch = Pitch(26);
+    pub const D1S: Pitch = Pitch(27);
+    pub const E1: Pitch = Pitch(28);
+    pub const F1: Pitch = Pitch(29);
+    pub const F1S: Pitch = Pitch(30);
+    pub const G1: Pitch = Pitch(31);
+    pub const G1S: Pitch = Pitch(32);
+    pub const A1: Pitch = Pitch(33);
+    pub const A1S: Pitch = Pitch(34);
+    pub const B1: Pitch = Pitch(35);
+    pub const C2: Pitch = Pitch(36);
+    pub const C2S: Pitch = Pitch(37);
+    pub const D2: Pitch = Pitch(38);
+    pub const D2S: Pitch = Pitch(39);
+    pub const E2: Pitch = Pitch(40);
+    pub const F2: Pitch = Pitch(41);
+    pub const F2S: Pitch = Pitch(42);
+    pub const G2: Pitch = Pitch(43);
+    pub const G2S: Pitch = Pitch(44);
+    pub const A2: Pitch = Pitch(45);
+    pub const A2S: Pitch = Pitch(46);
+    pub const B2: Pitch = Pitch(47);
+    pub const C3: Pitch = Pitch(48);
+    pub const C3S: Pitch = Pitch(49);
+    pub const D3: Pitch = Pitch(50);
+    pub const D3S: Pitch = Pitch(51);
+    pub const E3: Pitch = Pitch(52);
+    pub const F3: Pitch = Pitch(53);
+    pub const F3S: Pitch = Pitch(54);
+    pub const G3: Pitch = Pitch(55);
+    pub const G3S: Pitch = Pitch(56);
+    pub const A3: Pitch = Pitch(57);
+    pub const A3S: Pitch = Pitch(58);
+    pub const B3: Pitch = Pitch(59);
+    pub const C4: Pitch = Pitch(60);
+    pub const C4S: Pitch = Pitch(61);
+    pub const D4: Pitch = Pitch(62);
+    pub const D4S: Pitch = Pitch(63);
+    pub const E4: Pitch = Pitch(64);
+    pub const F4: Pitch = Pitch(65);
+    pub const F4S: Pitch = Pitch(66);
+    pub const G4: Pitch = Pitch(67);
+    pub const G4S: Pitch = Pitch(68);
+    pub const A4: Pitch = Pitch(69);
+    pub const A4S: Pitch = Pitch(70);
+    pub const B4: Pitch = Pitch(71);
+    pub const C5: Pitch = Pitch(72);
+    pub const C5S: Pitch = Pitch(73);
+    pub const D5: Pitch = Pitch(74);
+    pub const D5S: Pitch = Pitch(75);
+    pub const E5: Pitch = Pitch(76);
+    pub const F5: Pitch = Pitch(77);
+    pub const F5S: Pitch = Pitch(78);
+    pub const G5: Pitch = Pitch(79);
+    pub const G5S: Pitch = Pitch(80);
+    pub const A5: Pitch = Pitch(81);
+    pub const A5S: Pitch = Pitch(82);
+    pub const B5: Pitch = Pitch(83);
+    pub const C6: Pitch = Pitch(84);
+    pub const C6S: Pitch = Pitch(85);
+    pub const D6: Pitch = Pitch(86);
+    pub const D6S: Pitch = Pitch(87);
+    pub const E6: Pitch = Pitch(88);
+    pub const F6: Pitch = Pitch(89);
+    pub const F6S: Pitch = Pitch(90);
+    pub const G6: Pitch = Pitch(91);
+    pub const G6S: Pitch = Pitch(92);
+    pub const A6: Pitch = Pitch(93);
+    pub const A6S: Pitch = Pitch(94);
+    pub const B6: Pitch = Pitch(95);
+    pub const C7: Pitch = Pitch(96);
+    pub const C7S: Pitch = Pitch(97);
+    pub const D7: Pitch = Pitch(98);
+    pub const D7S: Pitch = Pitch(99);
+    pub const E7: Pitch = Pitch(100);
+    pub const F7: Pitch = Pitch(101);
+    pub const F7S: Pitch = Pitch(102);
+    pub const G7: Pitch = Pitch(103);
+    pub const G7S: Pitch = Pitch(104);
+    pub const A7: Pitch = Pitch(105);
+    pub const A7S: Pitch = Pitch(106);
+    pub const B7: Pitch = Pitch(107);
+    pub const C8: Pitch = Pitch(108);
+    pub const C8S: Pitch = Pitch(109);
+    pub const D8: Pitch = Pitch(110);
+    pub const D8S: Pitch = Pitch(111);
+    pub const E8: Pitch = Pitch(112);
+    pub const F8: Pitch = Pitch(113);
+    pub const F8S: Pitch = Pitch(114);
+    pub const G8: Pitch = Pitch(115);
+    pub const G8S: Pitch = Pitch(116);
+    pub const A8: Pitch = Pitch(117);
+    pub const A8S: Pitch = Pitch(118);
+    pub const B8: Pitch = Pitch(119);
+}
+
+#[allow(dead_code)]
+impl Pitch {
+    // Concert pitch of A4, in Hz
+    pub const REFERENCE: f64 = 440.0;
+    const REFERENCE_KEY: u8 = 69;
+
+    pub fn from_key(key: u8) -> Option<Self> {
+        if key <= 127 {
+            Some(Self(key))
+        } else {
+            None
+        }
+    }
+
+    pub fn key(&self) -> u8 {
+        self.0
+    }
+
+    // Twelve-tone equal temperament with A4 = 440 Hz
+    pub fn frequency(&self) -> f32 {
+        self.frequency_with_reference(Self::REFERENCE) as f32
+    }
+
+    // Twelve-tone equal temperament with A4 = `reference` Hz
+    pub fn frequency_with_reference(&self, reference: f64) -> f64 {
+        reference * 2f64.powf((self.0 as f64 - Self::REFERENCE_KEY as f64) / 12.0)
+    }
+}
+
+// Which accidental black keys are written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Spelling {
+    Sharps,
+    Flats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsePitchError {
+    // Doesn't start with a letter from A to G
+    InvalidLetter(String),
+    // Missing or malformed octave number
+    InvalidOctave(String),
+    // Spells a key outside of 0 (C-1) to 127 (G9)
+    OutOfRange(String),
+}
+
+impl std::fmt::Display for ParsePitchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParsePitchError::InvalidLetter(s) => write!(f, "invalid note letter in {:?}", s),
+            ParsePitchError::InvalidOctave(s) => write!(f, "invalid octave in {:?}", s),
+            ParsePitchError::OutOfRange(s) => write!(f, "{:?} is outside the MIDI range", s),
+        }
+    }
+}
+
+impl std::error::Error for ParsePitchError {}
+
+impl Pitch {
+    const SHARP_NAMES: [&'static str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    const FLAT_NAMES: [&'static str; 12] = [
+        "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B",
+    ];
+
+    // Octave number, C4 being middle C so key 0 is in octave -1
+    pub fn octave(&self) -> i8 {
+        (self.0 / 12) as i8 - 1
+    }
+
+    // Name and octave like "C#4" or "Db4" depending on `spelling`
+    pub fn name(&self, spelling: Spelling) -> String {
+        let names = match spelling {
+            Spelling::Sharps => &Self::SHARP_NAMES,
+            Spelling::Flats => &Self::FLAT_NAMES,
+        };
+
+        format!("{}{}", names[(self.0 % 12) as usize], self.octave())
+    }
+}
+
+impl std::fmt::Display for Pitch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name(Spelling::Sharps))
+    }
+}
+
+/*
+ * Accepts a letter, any number of accidentals ("#", "♯", "x" for a double
+ * sharp, "b", "♭") and a possibly negative octave: "C#4", "Bb3", "A-1", "Fx2"
+ */
+impl std::str::FromStr for Pitch {
+    type Err = ParsePitchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.trim().chars().peekable();
+
+        let semitone: i32 = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(ParsePitchError::InvalidLetter(s.to_string())),
+        };
+
+        let out_of_range = || ParsePitchError::OutOfRange(s.to_string());
+
+        let mut accidental: i32 = 0;
+        while let Some(c) = chars.peek() {
+            let step = match c {
+                '#' | '♯' => 1,
+                'x' | '𝄪' => 2,
+                'b' | '♭' => -1,
+                '𝄫' => -2,
+                _ => break,
+            };
+            accidental = accidental.checked_add(step).ok_or_else(out_of_range)?;
+            chars.next();
+        }
+
+        // Only a minus sign, i32 parsing would take "+4" too
+        let octave = chars.collect::<String>();
+        let octave: i32 = match octave.starts_with('+') {
+            true => None,
+            false => octave.parse().ok(),
+        }
+        .ok_or_else(|| ParsePitchError::InvalidOctave(s.to_string()))?;
+
+        let key = octave
+            .checked_add(1)
+            .and_then(|o| o.checked_mul(12))
+            .and_then(|k| k.checked_add(semitone))
+            .and_then(|k| k.checked_add(accidental))
+            .ok_or_else(out_of_range)?;
+
+        u8::try_from(key)
+            .ok()
+            .and_then(Pitch::from_key)
+            .ok_or_else(out_of_range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Pitch, ParsePitchError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(parse("C#4"), Ok(Pitch::C4S));
+        assert_eq!(parse("Bb3"), Ok(Pitch::A3S));
+        assert_eq!(parse("A-1"), Ok(Pitch(9)));
+        assert_eq!(parse("Fx2"), Ok(Pitch::G2));
+        assert_eq!(parse("Dbb4"), Ok(Pitch::C4));
+        assert_eq!(parse("D𝄫4"), Ok(Pitch::C4));
+        assert_eq!(parse("E♭4"), Ok(Pitch::D4S));
+        assert_eq!(parse("f♯4"), Ok(Pitch::F4S));
+        // Accidentals may cross the octave
+        assert_eq!(parse("Cb4"), Ok(Pitch::B3));
+        assert_eq!(parse("B#3"), Ok(Pitch::C4));
+        assert_eq!(parse("C-1"), Ok(Pitch(0)));
+        assert_eq!(parse("G9"), Ok(Pitch(127)));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        assert_eq!(
+            parse("H4"),
+            Err(ParsePitchError::InvalidLetter("H4".into()))
+        );
+        assert_eq!(parse(""), Err(ParsePitchError::InvalidLetter("".into())));
+        assert_eq!(parse("C"), Err(ParsePitchError::InvalidOctave("C".into())));
+        assert_eq!(
+            parse("C+4"),
+            Err(ParsePitchError::InvalidOctave("C+4".into()))
+        );
+        assert_eq!(
+            parse("C#+4"),
+            Err(ParsePitchError::InvalidOctave("C#+4".into()))
+        );
+        assert_eq!(
+            parse("C#x"),
+            Err(ParsePitchError::InvalidOctave("C#x".into()))
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_names() {
+        for s in [
+            "Cb-1",
+            "G#9",
+            "A9",
+            "C2147483647",
+            "B178956969",
+            "C-2147483648",
+        ] {
+            assert_eq!(
+                parse(s),
+                Err(ParsePitchError::OutOfRange(s.into())),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn frequencies() {
+        assert_eq!(Pitch::A4.frequency(), 440.0);
+        assert_eq!(Pitch::A0.frequency(), 27.5);
+        assert!((Pitch::C4.frequency() - 261.6256).abs() < 1e-3);
+        assert_eq!(Pitch::A5.frequency(), 880.0);
+
+        assert_eq!(Pitch::A4.frequency_with_reference(432.0), 432.0);
+        assert!((Pitch::C4.frequency_with_reference(432.0) - 256.8687).abs() < 1e-3);
+    }
+
+    #[test]
+    fn names_round_trip_in_both_spellings() {
+        assert_eq!(Pitch::C4S.name(Spelling::Sharps), "C#4");
+        assert_eq!(Pitch::C4S.name(Spelling::Flats), "Db4");
+        assert_eq!(Pitch::A3S.to_string(), "A#3");
+        assert_eq!(Pitch(0).to_string(), "C-1");
+
+        for key in 0..=127 {
+            let pitch = Pitch(key);
+            for spelling in [Spelling::Sharps, Spelling::Flats] {
+                assert_eq!(parse(&pitch.name(spelling)), Ok(pitch));
+            }
+        }
+    }
 }
@@ -12,15 +12,19 @@ pub struct Roll {
 // }
 
 impl Roll {
-    const TEMPO: f32 = 84.0;
-    const TIME_SIGNATURE: f32 = 1.0 / 4.0;
+    // A Roll step is a sixteenth note
+    pub const STEPS_PER_QUARTER: f32 = 4.0;
 
     pub fn new(v: f32) -> Self {
         Self { v }
     }
 
-    pub fn seconds(&self) -> f32 {
-        self.v * Self::TIME_SIGNATURE * 60.0 / Self::TEMPO
+    pub fn seconds(&self, timeline: &Timeline) -> f32 {
+        timeline.seconds(*self)
+    }
+
+    pub fn from_seconds(s: f32, timeline: &Timeline) -> Self {
+        timeline.roll(s)
     }
 }
 
@@ -31,3 +35,464 @@ impl std::ops::Add<Roll> for Roll {
         Roll::new(self.v + rhs.v)
     }
 }
+
+/*********************/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    // Note value of a beat, 4 for quarter notes
+    pub denominator: u8,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self {
+            numerator: 4,
+            denominator: 4,
+        }
+    }
+}
+
+impl TimeSignature {
+    // Ticks in one beat at MusicalTime::PPQ
+    pub fn beat_ticks(&self) -> i64 {
+        MusicalTime::PPQ * 4 / self.denominator.max(1) as i64
+    }
+
+    // Ticks in one bar at MusicalTime::PPQ
+    pub fn bar_ticks(&self) -> i64 {
+        (self.beat_ticks() * self.numerator as i64).max(1)
+    }
+}
+
+/*********************/
+/*
+ * Tempo and time signature of a song, Roll positions are resolved against it.
+ * Tempos are in quarter notes per minute and hold until the next change.
+ */
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    tempos: Vec<(Roll, f32)>,
+    time_signatures: Vec<(Roll, TimeSignature)>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_TEMPO)
+    }
+}
+
+impl Timeline {
+    pub const DEFAULT_TEMPO: f32 = 84.0;
+
+    // Constant `bpm` in 4/4
+    pub fn new(bpm: f32) -> Self {
+        Self {
+            tempos: vec![(Roll::new(0.0), bpm)],
+            time_signatures: vec![(Roll::new(0.0), TimeSignature::default())],
+        }
+    }
+
+    // Replaces any tempo change already set on `at`
+    pub fn set_tempo(&mut self, at: Roll, bpm: f32) {
+        self.tempos.retain(|(r, _)| r.v != at.v);
+        self.tempos.push((at, bpm));
+        self.tempos.sort_by(|a, b| a.0.v.total_cmp(&b.0.v));
+    }
+
+    // Replaces any time signature already set on `at`
+    pub fn set_time_signature(&mut self, at: Roll, time_signature: TimeSignature) {
+        self.time_signatures.retain(|(r, _)| r.v != at.v);
+        self.time_signatures.push((at, time_signature));
+        self.time_signatures.sort_by(|a, b| a.0.v.total_cmp(&b.0.v));
+    }
+
+    pub fn tempos(&self) -> &[(Roll, f32)] {
+        &self.tempos
+    }
+
+    pub fn time_signatures(&self) -> &[(Roll, TimeSignature)] {
+        &self.time_signatures
+    }
+
+    #[allow(dead_code)]
+    pub fn tempo_at(&self, at: Roll) -> f32 {
+        self.tempos
+            .iter()
+            .take_while(|(r, _)| r.v <= at.v)
+            .last()
+            .map_or(self.tempos[0].1, |(_, bpm)| *bpm)
+    }
+
+    #[allow(dead_code)]
+    pub fn time_signature_at(&self, at: Roll) -> TimeSignature {
+        self.time_signatures
+            .iter()
+            .take_while(|(r, _)| r.v <= at.v)
+            .last()
+            .map_or(self.time_signatures[0].1, |(_, ts)| *ts)
+    }
+
+    // Seconds one Roll step lasts at `bpm`
+    fn step_seconds(bpm: f32) -> f64 {
+        60.0 / (bpm as f64 * Roll::STEPS_PER_QUARTER as f64)
+    }
+
+    // Absolute time of `at`, integrating every tempo change before it
+    pub fn seconds(&self, at: Roll) -> f32 {
+        let mut seconds = 0.0;
+        let mut position = 0.0;
+        let mut bpm = self.tempos[0].1;
+
+        for (r, next) in &self.tempos {
+            if r.v >= at.v {
+                break;
+            }
+
+            seconds += (r.v - position).max(0.0) as f64 * Self::step_seconds(bpm);
+            position = r.v.max(position);
+            bpm = *next;
+        }
+
+        (seconds + (at.v - position) as f64 * Self::step_seconds(bpm)) as f32
+    }
+
+    // Position reached after `seconds`, the inverse of `seconds`
+    pub fn roll(&self, seconds: f32) -> Roll {
+        let seconds = seconds as f64;
+        let mut elapsed = 0.0;
+        let mut position = 0.0;
+        let mut bpm = self.tempos[0].1;
+
+        for (r, next) in &self.tempos {
+            let length = (r.v - position).max(0.0) as f64 * Self::step_seconds(bpm);
+
+            if elapsed + length > seconds {
+                break;
+            }
+
+            elapsed += length;
+            position = r.v.max(position);
+            bpm = *next;
+        }
+
+        Roll::new(position + ((seconds - elapsed) / Self::step_seconds(bpm)) as f32)
+    }
+}
+
+/*********************/
+// Position in ticks from the start of the song, MusicalTime::PPQ to the quarter note
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MusicalTime {
+    pub ticks: i64,
+}
+
+#[allow(dead_code)]
+impl MusicalTime {
+    pub const PPQ: i64 = 960;
+
+    pub fn new(ticks: i64) -> Self {
+        Self { ticks }
+    }
+
+    // Start of `bar`, counting from 1 like DAWs
+    pub fn bar(bar: i32, timeline: &Timeline) -> Self {
+        Self::from_bars_beats_ticks(BarsBeatsTicks::new(bar, 1, 0), timeline)
+    }
+
+    // `numerator` / `denominator` of this time, rounded to the nearest tick
+    pub fn scale(&self, numerator: i64, denominator: i64) -> Self {
+        Self::new((self.ticks as f64 * numerator as f64 / denominator as f64).round() as i64)
+    }
+
+    pub fn seconds(&self, timeline: &Timeline) -> f32 {
+        Roll::from(*self).seconds(timeline)
+    }
+
+    pub fn from_seconds(s: f32, timeline: &Timeline) -> Self {
+        Roll::from_seconds(s, timeline).into()
+    }
+
+    pub fn bars_beats_ticks(&self, timeline: &Timeline) -> BarsBeatsTicks {
+        timeline.bars_beats_ticks(*self)
+    }
+
+    pub fn from_bars_beats_ticks(bbt: BarsBeatsTicks, timeline: &Timeline) -> Self {
+        timeline.musical_time(bbt)
+    }
+}
+
+impl From<Roll> for MusicalTime {
+    fn from(r: Roll) -> Self {
+        Self::new((r.v as f64 * Self::PPQ as f64 / Roll::STEPS_PER_QUARTER as f64).round() as i64)
+    }
+}
+
+impl From<MusicalTime> for Roll {
+    fn from(m: MusicalTime) -> Self {
+        Roll::new(
+            (m.ticks as f64 * Roll::STEPS_PER_QUARTER as f64 / MusicalTime::PPQ as f64) as f32,
+        )
+    }
+}
+
+impl std::ops::Add<MusicalTime> for MusicalTime {
+    type Output = Self;
+
+    fn add(self, rhs: MusicalTime) -> Self::Output {
+        Self::new(self.ticks + rhs.ticks)
+    }
+}
+
+impl std::ops::Sub<MusicalTime> for MusicalTime {
+    type Output = Self;
+
+    fn sub(self, rhs: MusicalTime) -> Self::Output {
+        Self::new(self.ticks - rhs.ticks)
+    }
+}
+
+impl std::ops::AddAssign<MusicalTime> for MusicalTime {
+    fn add_assign(&mut self, rhs: MusicalTime) {
+        self.ticks += rhs.ticks;
+    }
+}
+
+impl std::ops::SubAssign<MusicalTime> for MusicalTime {
+    fn sub_assign(&mut self, rhs: MusicalTime) {
+        self.ticks -= rhs.ticks;
+    }
+}
+
+impl std::ops::Mul<i64> for MusicalTime {
+    type Output = Self;
+
+    fn mul(self, rhs: i64) -> Self::Output {
+        Self::new(self.ticks * rhs)
+    }
+}
+
+// Multiplies by the (numerator, denominator) ratio, see MusicalTime::scale
+impl std::ops::Mul<(i64, i64)> for MusicalTime {
+    type Output = Self;
+
+    fn mul(self, (numerator, denominator): (i64, i64)) -> Self::Output {
+        self.scale(numerator, denominator)
+    }
+}
+
+/*********************/
+// Bar and beat count from 1, ticks from 0 at MusicalTime::PPQ: "12.3.240"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BarsBeatsTicks {
+    pub bar: i32,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+impl BarsBeatsTicks {
+    pub fn new(bar: i32, beat: u32, tick: u32) -> Self {
+        Self { bar, beat, tick }
+    }
+}
+
+impl std::fmt::Display for BarsBeatsTicks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.bar, self.beat, self.tick)
+    }
+}
+
+impl std::str::FromStr for BarsBeatsTicks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('.');
+        let invalid = || format!("invalid bars.beats.ticks {:?}", s);
+
+        let bar = parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(invalid)?;
+        let beat = match parts.next() {
+            Some(p) => p.parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+        let tick = match parts.next() {
+            Some(p) => p.parse().map_err(|_| invalid())?,
+            None => 0,
+        };
+
+        if beat == 0 || parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self::new(bar, beat, tick))
+    }
+}
+
+impl Timeline {
+    // Time signatures with their start tick, a change always starts a new bar
+    fn meters(&self) -> impl Iterator<Item = (i64, TimeSignature)> + '_ {
+        self.time_signatures
+            .iter()
+            .map(|(r, ts)| (MusicalTime::from(*r).ticks, *ts))
+    }
+
+    pub fn bars_beats_ticks(&self, time: MusicalTime) -> BarsBeatsTicks {
+        let mut meters = self.meters();
+        let (mut start, mut ts) = meters.next().unwrap_or((0, TimeSignature::default()));
+        let mut bar: i64 = 0;
+
+        for (next_start, next) in meters {
+            if next_start > time.ticks {
+                break;
+            }
+
+            // Bars started before the change, a partial one counts as whole
+            bar += (next_start - start + ts.bar_ticks() - 1).div_euclid(ts.bar_ticks());
+            start = next_start;
+            ts = next;
+        }
+
+        let offset = time.ticks - start;
+        let within = offset.rem_euclid(ts.bar_ticks());
+
+        BarsBeatsTicks::new(
+            (bar + offset.div_euclid(ts.bar_ticks()) + 1) as i32,
+            (within / ts.beat_ticks() + 1) as u32,
+            (within % ts.beat_ticks()) as u32,
+        )
+    }
+
+    pub fn musical_time(&self, bbt: BarsBeatsTicks) -> MusicalTime {
+        let target = bbt.bar as i64 - 1;
+        let mut meters = self.meters();
+        let (mut start, mut ts) = meters.next().unwrap_or((0, TimeSignature::default()));
+        let mut bar: i64 = 0;
+
+        for (next_start, next) in meters {
+            let bars = (next_start - start + ts.bar_ticks() - 1).div_euclid(ts.bar_ticks());
+
+            if bar + bars > target {
+                break;
+            }
+
+            bar += bars;
+            start = next_start;
+            ts = next;
+        }
+
+        MusicalTime::new(
+            start
+                + (target - bar) * ts.bar_ticks()
+                + (bbt.beat.max(1) as i64 - 1) * ts.beat_ticks()
+                + bbt.tick as i64,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbt(s: &str) -> BarsBeatsTicks {
+        s.parse().unwrap()
+    }
+
+    // 120 BPM 4/4, 60 BPM from bar 3 and 3/4 from bar 5
+    fn timeline() -> Timeline {
+        let mut timeline = Timeline::new(120.0);
+        timeline.set_tempo(Roll::new(32.0), 60.0);
+        timeline.set_time_signature(
+            Roll::new(64.0),
+            TimeSignature {
+                numerator: 3,
+                denominator: 4,
+            },
+        );
+        timeline
+    }
+
+    #[test]
+    fn bars_beats_ticks_follow_tempo_and_meter() {
+        let timeline = timeline();
+        let at = |seconds: f32| {
+            MusicalTime::from_seconds(seconds, &timeline).bars_beats_ticks(&timeline)
+        };
+
+        assert_eq!(at(0.0), bbt("1.1.0"));
+        assert_eq!(at(2.25), bbt("2.1.480"));
+        // Bars last 4 s from the tempo change on
+        assert_eq!(at(4.0), bbt("3.1.0"));
+        assert_eq!(at(9.0), bbt("4.2.0"));
+        // And 3 s once in 3/4
+        assert_eq!(at(12.0), bbt("5.1.0"));
+        assert_eq!(at(13.5), bbt("5.2.480"));
+        assert_eq!(at(15.0), bbt("6.1.0"));
+    }
+
+    #[test]
+    fn seconds_round_trip_through_bars_beats_ticks() {
+        let timeline = timeline();
+
+        for i in 0..400 {
+            let seconds = i as f32 * 0.0437;
+            let bbt = MusicalTime::from_seconds(seconds, &timeline).bars_beats_ticks(&timeline);
+            let back = MusicalTime::from_bars_beats_ticks(bbt, &timeline).seconds(&timeline);
+
+            // Within a tick at the slowest tempo
+            assert!(
+                (back - seconds).abs() < 1.5e-3,
+                "{} s is {} then {} s",
+                seconds,
+                bbt,
+                back
+            );
+        }
+    }
+
+    #[test]
+    fn pickup_bar_counts_as_a_whole_bar() {
+        // One beat of 1/4 before the song starts in 4/4
+        let mut timeline = Timeline::new(120.0);
+        timeline.set_time_signature(
+            Roll::new(0.0),
+            TimeSignature {
+                numerator: 1,
+                denominator: 4,
+            },
+        );
+        timeline.set_time_signature(Roll::new(4.0), TimeSignature::default());
+
+        let at = |steps: f32| MusicalTime::from(Roll::new(steps)).bars_beats_ticks(&timeline);
+        assert_eq!(at(0.0), bbt("1.1.0"));
+        assert_eq!(at(2.0), bbt("1.1.480"));
+        assert_eq!(at(4.0), bbt("2.1.0"));
+        assert_eq!(at(26.0), bbt("3.2.480"));
+
+        assert_eq!(MusicalTime::bar(2, &timeline), MusicalTime::new(960));
+        assert_eq!(MusicalTime::bar(3, &timeline).seconds(&timeline), 2.5);
+    }
+
+    #[test]
+    fn parses_and_displays_bars_beats_ticks() {
+        assert_eq!(bbt("3.2.480"), BarsBeatsTicks::new(3, 2, 480));
+        assert_eq!(bbt(" 12.3.240 ").to_string(), "12.3.240");
+        assert_eq!(bbt("3"), BarsBeatsTicks::new(3, 1, 0));
+        assert_eq!(bbt("3.4"), BarsBeatsTicks::new(3, 4, 0));
+        assert_eq!(bbt("-1.1.0").to_string(), "-1.1.0");
+
+        for s in [
+            "",
+            "a.1.0",
+            "3.0.0",
+            "3.2.480.1",
+            "3..1",
+            "3.-1.0",
+            "3.2.x",
+            "3.2.480 1",
+        ] {
+            assert!(s.parse::<BarsBeatsTicks>().is_err(), "{:?} parsed", s);
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use std::{f64::consts::PI, rc::Rc};
+
+use crate::{
+    filter::{FilterChannel, FilterModel, FilterType},
+    instrument::{Envelope, Note},
+    io::{Stereo, WAV},
+    pitch::Pitch,
+    wavetable::hermite,
+};
+
+// Recorded audio, mono files play the same on both sides
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub frames: Vec<Stereo>,
+    pub sample_rate: u32,
+}
+
+#[allow(dead_code)]
+impl Sample {
+    pub fn new(frames: Vec<Stereo>, sample_rate: u32) -> Self {
+        Self {
+            frames,
+            sample_rate,
+        }
+    }
+
+    // First two channels of a WAV file
+    pub fn load(filename: &str) -> std::io::Result<Self> {
+        let data = WAV::load(filename)?;
+        let channels = data.channels as usize;
+
+        let frames = data
+            .samples
+            .chunks_exact(channels)
+            .map(|c| Stereo::new(c[0], c[1.min(channels - 1)]))
+            .collect();
+
+        Ok(Self::new(frames, data.sample_rate))
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/*********************/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum LoopMode {
+    // Plays to the end once, or until the note is over
+    None,
+    // Plays to the end once, whenever the note ends
+    OneShot,
+    // Jumps back to the loop start on reaching its end
+    Forward,
+    // Bounces between the loop points
+    PingPong,
+    // Loops forward while the note is held, then plays on to the end
+    Sustain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Interpolation {
+    Linear,
+    // 4 point Hermite
+    Cubic,
+    // Windowed sinc, also band limited when pitched up
+    Sinc,
+}
+
+/*********************/
+// A sample and the keys and velocities it answers to, bounds included
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub sample: Rc<Sample>,
+    pub keys: (u8, u8),
+    pub velocities: (u8, u8),
+    // Key the sample was recorded at, and cents to correct its tuning
+    pub root_key: u8,
+    pub tune: f32,
+    // Share of a semitone each key moves the pitch by, 0 plays every key at the root pitch
+    pub key_tracking: f32,
+    pub gain: f32,
+    pub pan: f32,
+    pub loop_mode: LoopMode,
+    // Frames, the end is excluded
+    pub loop_start: usize,
+    pub loop_end: usize,
+    // Amplitude envelope of this zone alone
+    pub envelope: Option<Envelope>,
+    // Low pass cutoff in Hz and Q
+    pub filter: Option<(f32, f32)>,
+}
+
+#[allow(dead_code)]
+impl Zone {
+    // Every key and velocity, with no loop
+    pub fn new(sample: Rc<Sample>, root_key: u8) -> Self {
+        Self {
+            loop_end: sample.len(),
+            sample,
+            keys: (0, 127),
+            velocities: (0, 127),
+            root_key,
+            tune: 0.0,
+            key_tracking: 1.0,
+            gain: 1.0,
+            pan: 0.0,
+            loop_mode: LoopMode::None,
+            loop_start: 0,
+            envelope: None,
+            filter: None,
+        }
+    }
+
+    pub fn matches(&self, key: u8, velocity: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&key)
+            && (self.velocities.0..=self.velocities.1).contains(&velocity)
+    }
+
+    // Whether the loop is played at `t`
+    fn looped(&self, t: f32, note: &Note) -> bool {
+        let held = self.loop_mode != LoopMode::Sustain || t < note.end_time;
+
+        matches!(
+            self.loop_mode,
+            LoopMode::Forward | LoopMode::PingPong | LoopMode::Sustain
+        ) && held
+            && self.loop_start < self.loop_end
+            && self.loop_end <= self.sample.len()
+    }
+
+    // Frame `i`, wrapped into the loop past its end when `looped`
+    fn frame(&self, i: isize, looped: bool) -> Stereo {
+        let frames = &self.sample.frames;
+        let (start, end) = (self.loop_start as isize, self.loop_end as isize);
+
+        let i = if looped && i >= end {
+            match self.loop_mode {
+                // Mirrored around the last frame of the loop
+                LoopMode::PingPong => (2 * (end - 1) - i).max(start),
+                _ => start + (i - start).rem_euclid(end - start),
+            }
+        } else {
+            i
+        };
+
+        match usize::try_from(i).ok().and_then(|i| frames.get(i)) {
+            Some(frame) => *frame,
+            None => Stereo::default(),
+        }
+    }
+
+    fn read(&self, position: f64, step: f64, interpolation: Interpolation, looped: bool) -> Stereo {
+        let i = position.floor() as isize;
+        let x = (position - position.floor()) as f32;
+        let frame = |i: isize| self.frame(i, looped);
+
+        match interpolation {
+            Interpolation::Linear => frame(i) * (1.0 - x) + frame(i + 1) * x,
+            Interpolation::Cubic => {
+                let [y0, y1, y2, y3] = [frame(i - 1), frame(i), frame(i + 1), frame(i + 2)];
+                let x = x as f64;
+
+                Stereo::new(
+                    hermite([y0.left, y1.left, y2.left, y3.left].map(f64::from), x) as f32,
+                    hermite([y0.right, y1.right, y2.right, y3.right].map(f64::from), x) as f32,
+                )
+            }
+            Interpolation::Sinc => {
+                // The kernel widens to filter out what would alias when pitched up
+                const TAPS: isize = 8;
+                let scale = step.abs().max(1.0);
+                let half = (TAPS as f64 * scale / 2.0).ceil() as isize;
+
+                let mut v = Stereo::default();
+                for k in (i - half + 1)..=(i + half) {
+                    let d = (position - k as f64) / scale;
+                    let window = d / (TAPS as f64 / 2.0);
+
+                    if window.abs() >= 1.0 {
+                        continue;
+                    }
+
+                    let sinc = if d == 0.0 {
+                        1.0
+                    } else {
+                        (PI * d).sin() / (PI * d)
+                    };
+                    // Blackman window
+                    let blackman =
+                        0.42 + 0.5 * (PI * window).cos() + 0.08 * (2.0 * PI * window).cos();
+
+                    v += frame(k) * (sinc * blackman / scale) as f32;
+                }
+
+                v
+            }
+        }
+    }
+}
+
+/*********************/
+// Plays the zones matching the key and velocity of each note, layered
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    pub zones: Vec<Zone>,
+    pub interpolation: Interpolation,
+}
+
+impl Sampler {
+    fn velocity(note: &Note) -> u8 {
+        (note.velocity * 127.0).round().clamp(0.0, 127.0) as u8
+    }
+
+    pub fn play(&self, t: f32, f: f32, note: &Note, state: &mut SamplerState) -> Stereo {
+        if !state.started {
+            state.started = true;
+            state.layers = self
+                .zones
+                .iter()
+                .enumerate()
+                .filter(|(_, z)| z.matches(note.pitch.key(), Self::velocity(note)))
+                .map(|(i, z)| Layer {
+                    zone: i,
+                    position: 0.0,
+                    direction: 1.0,
+                    filter: z.filter.map(|(cutoff, q)| {
+                        [FilterChannel::new(
+                            FilterModel::StateVariable,
+                            FilterType::LowPass,
+                            cutoff,
+                            q,
+                        ); 2]
+                    }),
+                })
+                .collect();
+        }
+
+        let mut v = Stereo::default();
+
+        for layer in state.layers.iter_mut() {
+            let zone = &self.zones[layer.zone];
+
+            if layer.position >= zone.sample.len() as f64 {
+                continue;
+            }
+
+            let root = Pitch::from_key(zone.root_key)
+                .map_or(Pitch::REFERENCE, |p| p.frequency() as f64)
+                * 2f64.powf(zone.tune as f64 / 1200.0);
+            let step = (f as f64 / root).powf(zone.key_tracking as f64)
+                * zone.sample.sample_rate as f64
+                / WAV::SAMPLE_RATE as f64;
+            let looped = zone.looped(t, note);
+
+            let mut layer_v =
+                zone.read(layer.position, step, self.interpolation, looped) * zone.gain;
+
+            if let Some([left, right]) = &mut layer.filter {
+                layer_v = Stereo::new(left.run(layer_v.left), right.run(layer_v.right));
+            }
+
+            if let Some(e) = &zone.envelope {
+                layer_v = layer_v * e.play(t, note);
+            }
+
+            v += layer_v.balance(zone.pan);
+
+            layer.position += step * layer.direction;
+
+            if looped {
+                let (start, end) = (zone.loop_start as f64, zone.loop_end as f64);
+
+                match zone.loop_mode {
+                    LoopMode::PingPong => {
+                        if layer.position >= end - 1.0 {
+                            layer.position = 2.0 * (end - 1.0) - layer.position;
+                            layer.direction = -1.0;
+                        } else if layer.direction < 0.0 && layer.position <= start {
+                            layer.position = 2.0 * start - layer.position;
+                            layer.direction = 1.0;
+                        }
+                    }
+                    _ => {
+                        if layer.position >= end {
+                            layer.position = start + (layer.position - start) % (end - start);
+                        }
+                    }
+                }
+            }
+        }
+
+        state.finished = state
+            .layers
+            .iter()
+            .all(|l| l.position >= self.zones[l.zone].sample.len() as f64);
+
+        v
+    }
+
+    /*
+     * One shots and sustain loops play on to the end of their sample, and
+     * zones with their own envelope ring for its release
+     */
+    pub fn is_active(&self, t: f32, note: &Note, state: &SamplerState) -> bool {
+        state.started
+            && !state.finished
+            && state.layers.iter().any(|l| {
+                let zone = &self.zones[l.zone];
+
+                matches!(zone.loop_mode, LoopMode::OneShot | LoopMode::Sustain)
+                    || zone.envelope.is_some_and(|e| e.is_active(t, note))
+            })
+    }
+}
+
+// Playback of one zone
+#[derive(Debug, Clone)]
+struct Layer {
+    zone: usize,
+    // Frames
+    position: f64,
+    direction: f64,
+    filter: Option<[FilterChannel; 2]>,
+}
+
+// Per voice state of a Sampler
+#[derive(Debug, Clone, Default)]
+pub struct SamplerState {
+    started: bool,
+    finished: bool,
+    layers: Vec<Layer>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::Instrument;
+
+    // Frame `i` is worth `i`, so the output tells the position read
+    fn ramp(frames: usize) -> Rc<Sample> {
+        Rc::new(Sample::new(
+            (0..frames)
+                .map(|i| Stereo::new(i as f32, i as f32))
+                .collect(),
+            WAV::SAMPLE_RATE,
+        ))
+    }
+
+    // An A4 played on its root key, so every frame reads one sample point
+    fn note(velocity: f32, end_frame: u32) -> Note<'static> {
+        let mut note = Note::new(Pitch::A4, 1.0, 0.0, Instrument::DEFAULT, velocity);
+        note.start_time = 0.0;
+        note.end_time = end_frame as f32 / WAV::SAMPLE_RATE as f32;
+        note
+    }
+
+    fn looping(loop_mode: LoopMode) -> Zone {
+        Zone {
+            loop_mode,
+            loop_start: 4,
+            loop_end: 8,
+            ..Zone::new(ramp(12), 69)
+        }
+    }
+
+    // Positions read over `frames` frames, until the sampler stops
+    fn positions(sampler: &Sampler, note: &Note, frames: u32) -> Vec<f32> {
+        let mut state = SamplerState::default();
+        let mut read = vec![];
+
+        for n in 0..frames {
+            let t = n as f32 / WAV::SAMPLE_RATE as f32;
+            if t >= note.end_time && !sampler.is_active(t, note, &state) {
+                break;
+            }
+
+            let v = sampler.play(t, Pitch::A4.frequency(), note, &mut state);
+            read.push((v.left * 1e3).round() / 1e3);
+        }
+
+        read
+    }
+
+    fn sampler(zones: Vec<Zone>) -> Sampler {
+        Sampler {
+            zones,
+            interpolation: Interpolation::Linear,
+        }
+    }
+
+    #[test]
+    fn zones_are_picked_by_key_and_velocity() {
+        let constant = |v: f32| Rc::new(Sample::new(vec![Stereo::new(v, v); 4], WAV::SAMPLE_RATE));
+        let zone = |v, keys, velocities| Zone {
+            keys,
+            velocities,
+            ..Zone::new(constant(v), 69)
+        };
+        let zones = vec![
+            zone(1.0, (0, 68), (0, 127)),
+            zone(2.0, (69, 127), (0, 63)),
+            zone(4.0, (69, 127), (64, 127)),
+            // Layered over the soft ones
+            zone(8.0, (60, 80), (0, 31)),
+        ];
+        let sampler = sampler(zones);
+
+        let first = |pitch: Pitch, velocity: f32| {
+            let mut note = note(velocity, 4);
+            note.pitch = pitch;
+            let v = sampler.play(0.0, pitch.frequency(), &note, &mut SamplerState::default());
+            (v.left * 1e3).round() / 1e3
+        };
+
+        assert_eq!(first(Pitch::A3, 1.0), 1.0);
+        assert_eq!(first(Pitch::A4, 0.4), 2.0);
+        assert_eq!(first(Pitch::A4, 0.6), 4.0);
+        assert_eq!(first(Pitch::A4, 0.1), 10.0);
+        assert_eq!(first(Pitch::C6, 0.1), 2.0);
+    }
+
+    #[test]
+    fn forward_loops_wrap_to_their_start() {
+        let sampler = sampler(vec![looping(LoopMode::Forward)]);
+
+        assert_eq!(
+            positions(&sampler, &note(1.0, 16), 16),
+            [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 4.0, 5.0, 6.0, 7.0, 4.0, 5.0, 6.0, 7.0]
+        );
+    }
+
+    #[test]
+    fn ping_pong_loops_reflect_at_both_ends() {
+        let sampler = sampler(vec![looping(LoopMode::PingPong)]);
+
+        assert_eq!(
+            positions(&sampler, &note(1.0, 16), 16),
+            [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 6.0, 5.0, 4.0, 5.0, 6.0, 7.0, 6.0, 5.0]
+        );
+    }
+
+    #[test]
+    fn sustain_loops_play_the_tail_once_released() {
+        let sampler = sampler(vec![looping(LoopMode::Sustain)]);
+
+        // Released after 10 frames, in the second time round the loop
+        assert_eq!(
+            positions(&sampler, &note(1.0, 10), 100),
+            [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0]
+        );
+    }
+}
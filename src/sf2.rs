@@ -0,0 +1,829 @@
+/*
+ * SoundFont 2 (.sf2) banks, played through the Sampler
+ * Format: https://freepats.zenvoid.org/sf2/sfspec24.pdf
+ */
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    instrument::{Curve, Envelope},
+    io::Stereo,
+    midi::MIDIFile,
+    sampler::{Interpolation, LoopMode, Sample, Sampler, Zone},
+};
+
+#[derive(Debug)]
+pub enum SoundFontError {
+    Io(std::io::Error),
+    // Not a RIFF file of form "sfbk"
+    NotSoundFont,
+    MissingChunk(&'static str),
+    // Chunk cut short or whose length isn't a whole number of records
+    Malformed {
+        chunk: &'static str,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for SoundFontError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SoundFontError::Io(e) => write!(f, "I/O error: {}", e),
+            SoundFontError::NotSoundFont => write!(f, "not a SoundFont 2 file"),
+            SoundFontError::MissingChunk(id) => write!(f, "missing {:?} chunk", id),
+            SoundFontError::Malformed { chunk, message } => {
+                write!(f, "malformed {:?} chunk: {}", chunk, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SoundFontError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SoundFontError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SoundFontError {
+    fn from(e: std::io::Error) -> Self {
+        SoundFontError::Io(e)
+    }
+}
+
+/*********************/
+// Generator operators the playback engine understands
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+enum SF2Generator {
+    StartAddrsOffset = 0,
+    EndAddrsOffset = 1,
+    StartloopAddrsOffset = 2,
+    EndloopAddrsOffset = 3,
+    StartAddrsCoarseOffset = 4,
+    InitialFilterFc = 8,
+    InitialFilterQ = 9,
+    EndAddrsCoarseOffset = 12,
+    Pan = 17,
+    DelayVolEnv = 33,
+    AttackVolEnv = 34,
+    HoldVolEnv = 35,
+    DecayVolEnv = 36,
+    SustainVolEnv = 37,
+    ReleaseVolEnv = 38,
+    Instrument = 41,
+    KeyRange = 43,
+    VelRange = 44,
+    StartloopAddrsCoarseOffset = 45,
+    InitialAttenuation = 48,
+    EndloopAddrsCoarseOffset = 50,
+    CoarseTune = 51,
+    FineTune = 52,
+    SampleID = 53,
+    SampleModes = 54,
+    ScaleTuning = 56,
+    OverridingRootKey = 58,
+}
+
+impl SF2Generator {
+    const COUNT: usize = 61;
+
+    // Value of generators a zone leaves out
+    fn default_value(&self) -> i16 {
+        match self {
+            SF2Generator::InitialFilterFc => 13500,
+            SF2Generator::DelayVolEnv
+            | SF2Generator::AttackVolEnv
+            | SF2Generator::HoldVolEnv
+            | SF2Generator::DecayVolEnv
+            | SF2Generator::ReleaseVolEnv => -12000,
+            SF2Generator::KeyRange | SF2Generator::VelRange => 0x7F00u16 as i16,
+            SF2Generator::ScaleTuning => 100,
+            SF2Generator::OverridingRootKey => -1,
+            _ => 0,
+        }
+    }
+}
+
+// Amounts a zone sets, indexed by generator operator
+#[derive(Debug, Clone, Copy)]
+pub struct Generators([Option<i16>; SF2Generator::COUNT]);
+
+impl Default for Generators {
+    fn default() -> Self {
+        Self([None; SF2Generator::COUNT])
+    }
+}
+
+impl Generators {
+    pub fn get(&self, operator: u16) -> Option<i16> {
+        self.0.get(operator as usize).copied().flatten()
+    }
+
+    fn set(&mut self, operator: u16, amount: i16) {
+        if let Some(g) = self.0.get_mut(operator as usize) {
+            *g = Some(amount);
+        }
+    }
+
+    fn value(&self, g: SF2Generator) -> Option<i16> {
+        self.get(g as u16)
+    }
+
+    // Low and high bytes of a range amount
+    fn range(&self, g: SF2Generator) -> Option<(u8, u8)> {
+        self.value(g).map(|v| (v as u8, (v as u16 >> 8) as u8))
+    }
+}
+
+/*********************/
+// Generators of a zone, and the instrument or sample it plays
+#[derive(Debug, Clone)]
+pub struct SF2Zone {
+    pub generators: Generators,
+    pub link: usize,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    // Applies to every zone, added to what they set
+    pub global: Generators,
+    // Linked to instruments
+    pub zones: Vec<SF2Zone>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct SF2Instrument {
+    pub name: String,
+    pub global: Generators,
+    // Linked to samples
+    pub zones: Vec<SF2Zone>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct SampleHeader {
+    pub name: String,
+    // Sample points in the smpl chunk, the end is excluded
+    pub start: u32,
+    pub end: u32,
+    pub loop_start: u32,
+    pub loop_end: u32,
+    pub sample_rate: u32,
+    pub original_pitch: u8,
+    // Cents
+    pub pitch_correction: i8,
+}
+
+/*********************/
+#[derive(Debug)]
+pub struct SoundFont {
+    pub presets: Vec<Preset>,
+    pub instruments: Vec<SF2Instrument>,
+    pub samples: Vec<SampleHeader>,
+    data: Vec<i16>,
+    // Converted samples by (header, start, end), shared between samplers
+    cache: RefCell<HashMap<(usize, usize, usize), Rc<Sample>>>,
+}
+
+#[allow(dead_code)]
+impl SoundFont {
+    pub const PERCUSSION_BANK: u16 = 128;
+
+    pub fn load(filename: &str) -> Result<Self, SoundFontError> {
+        Self::parse(&std::fs::read(filename)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, SoundFontError> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+            return Err(SoundFontError::NotSoundFont);
+        }
+
+        let mut chunks = HashMap::new();
+        Self::read_chunks(&bytes[12..], &mut chunks)?;
+
+        let chunk = |id: &'static str, size: usize| -> Result<&[u8], SoundFontError> {
+            let data: &[u8] = chunks
+                .get(id.as_bytes())
+                .ok_or(SoundFontError::MissingChunk(id))?;
+
+            if !data.len().is_multiple_of(size) {
+                return Err(SoundFontError::Malformed {
+                    chunk: id,
+                    message: format!("{} bytes isn't a multiple of {}", data.len(), size),
+                });
+            }
+
+            Ok(data)
+        };
+
+        let data = chunk("smpl", 2)?
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+
+        let presets = Self::read_presets(chunk("phdr", 38)?, chunk("pbag", 4)?, chunk("pgen", 4)?);
+        let instruments =
+            Self::read_instruments(chunk("inst", 22)?, chunk("ibag", 4)?, chunk("igen", 4)?);
+
+        // The last record of every list only marks the end of the previous one
+        let shdr = chunk("shdr", 46)?;
+        let samples = shdr
+            .chunks_exact(46)
+            .take((shdr.len() / 46).saturating_sub(1))
+            .map(|r| SampleHeader {
+                name: Self::name(&r[0..20]),
+                start: u32_at(r, 20),
+                end: u32_at(r, 24),
+                loop_start: u32_at(r, 28),
+                loop_end: u32_at(r, 32),
+                sample_rate: u32_at(r, 36),
+                original_pitch: r[40],
+                pitch_correction: r[41] as i8,
+            })
+            .collect();
+
+        Ok(Self {
+            presets,
+            instruments,
+            samples,
+            data,
+            cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    // Flattens LIST chunks, keeping the first chunk of every id
+    fn read_chunks<'b>(
+        mut bytes: &'b [u8],
+        chunks: &mut HashMap<[u8; 4], &'b [u8]>,
+    ) -> Result<(), SoundFontError> {
+        while bytes.len() >= 8 {
+            let id: [u8; 4] = bytes[0..4].try_into().unwrap();
+            let length = u32_at(bytes, 4) as usize;
+            let body = bytes
+                .get(8..8 + length)
+                .ok_or_else(|| SoundFontError::Malformed {
+                    chunk: "RIFF",
+                    message: format!("{:?} is cut short", String::from_utf8_lossy(&id)),
+                })?;
+
+            if &id == b"LIST" && body.len() >= 4 {
+                Self::read_chunks(&body[4..], chunks)?;
+            } else {
+                chunks.entry(id).or_insert(body);
+            }
+
+            // Chunks are word aligned
+            bytes = bytes.get(8 + length + (length & 1)..).unwrap_or(&[]);
+        }
+
+        Ok(())
+    }
+
+    fn name(bytes: &[u8]) -> String {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+    }
+
+    /*
+     * Zones of `bags` [from, to), every bag pointing at its first generator.
+     * The zone not ending on a `link` generator, if first, is the global one.
+     */
+    fn read_zones(
+        bags: &[u8],
+        generators: &[u8],
+        (from, to): (usize, usize),
+        link: SF2Generator,
+    ) -> (Generators, Vec<SF2Zone>) {
+        let bag = |i: usize| bags.get(i * 4..i * 4 + 2).map(|b| u16_at(b, 0) as usize);
+        let mut global = Generators::default();
+        let mut zones = vec![];
+
+        for b in from..to {
+            let (Some(first), Some(last)) = (bag(b), bag(b + 1)) else {
+                break;
+            };
+
+            let mut zone = Generators::default();
+            for g in generators.chunks_exact(4).take(last).skip(first) {
+                zone.set(u16_at(g, 0), i16::from_le_bytes([g[2], g[3]]));
+            }
+
+            match zone.value(link) {
+                Some(l) => zones.push(SF2Zone {
+                    generators: zone,
+                    link: l as u16 as usize,
+                }),
+                None if b == from => global = zone,
+                None => {}
+            }
+        }
+
+        (global, zones)
+    }
+
+    fn read_presets(headers: &[u8], bags: &[u8], generators: &[u8]) -> Vec<Preset> {
+        let records: Vec<&[u8]> = headers.chunks_exact(38).collect();
+
+        records
+            .windows(2)
+            .map(|r| {
+                let bags_range = (u16_at(r[0], 24) as usize, u16_at(r[1], 24) as usize);
+                let (global, zones) =
+                    Self::read_zones(bags, generators, bags_range, SF2Generator::Instrument);
+
+                Preset {
+                    name: Self::name(&r[0][0..20]),
+                    program: u16_at(r[0], 20),
+                    bank: u16_at(r[0], 22),
+                    global,
+                    zones,
+                }
+            })
+            .collect()
+    }
+
+    fn read_instruments(headers: &[u8], bags: &[u8], generators: &[u8]) -> Vec<SF2Instrument> {
+        let records: Vec<&[u8]> = headers.chunks_exact(22).collect();
+
+        records
+            .windows(2)
+            .map(|r| {
+                let bags_range = (u16_at(r[0], 20) as usize, u16_at(r[1], 20) as usize);
+                let (global, zones) =
+                    Self::read_zones(bags, generators, bags_range, SF2Generator::SampleID);
+
+                SF2Instrument {
+                    name: Self::name(&r[0][0..20]),
+                    global,
+                    zones,
+                }
+            })
+            .collect()
+    }
+
+    pub fn preset(&self, bank: u16, program: u16) -> Option<&Preset> {
+        self.presets
+            .iter()
+            .find(|p| p.bank == bank && p.program == program)
+    }
+
+    /*
+     * Preset a General MIDI channel plays: channel 10 (9 from 0) uses the
+     * percussion bank, and missing banks fall back to the first one
+     */
+    pub fn midi_preset(&self, channel: u8, bank: u8, program: u8) -> Option<&Preset> {
+        let (program, bank) = (program as u16, bank as u16);
+
+        if channel == 9 {
+            return self
+                .preset(Self::PERCUSSION_BANK, program)
+                .or_else(|| self.preset(Self::PERCUSSION_BANK, 0));
+        }
+
+        self.preset(bank, program)
+            .or_else(|| self.preset(0, program))
+    }
+
+    /*
+     * Samplers for every (channel, bank, program) `midi` plays notes on, keyed
+     * the way MIDIFile::program_notes passes them so its instruments can
+     * borrow them. Programs without a preset are left out.
+     */
+    pub fn midi_samplers(&self, midi: &MIDIFile) -> HashMap<(u8, u8, u8), Sampler> {
+        let mut samplers = HashMap::new();
+
+        for n in midi.tracks().iter().flat_map(|t| &t.notes) {
+            let program = (n.channel, n.bank, n.program);
+            if samplers.contains_key(&program) {
+                continue;
+            }
+
+            if let Some(preset) = self.midi_preset(n.channel, n.bank, n.program) {
+                samplers.insert(program, self.sampler(preset));
+            }
+        }
+
+        samplers
+    }
+
+    // Sampler playing `preset`, one zone for each instrument zone of each preset zone
+    pub fn sampler(&self, preset: &Preset) -> Sampler {
+        let mut zones = vec![];
+
+        for preset_zone in &preset.zones {
+            let Some(instrument) = self.instruments.get(preset_zone.link) else {
+                continue;
+            };
+
+            for instrument_zone in &instrument.zones {
+                let generators = [
+                    &instrument_zone.generators,
+                    &instrument.global,
+                    &preset_zone.generators,
+                    &preset.global,
+                ];
+
+                if let Some(zone) = self.zone(instrument_zone.link, generators) {
+                    zones.push(zone);
+                }
+            }
+        }
+
+        Sampler {
+            zones,
+            interpolation: Interpolation::Sinc,
+        }
+    }
+
+    /*
+     * `generators` are the instrument zone, instrument global zone, preset
+     * zone and preset global zone. Instrument values are absolute and preset
+     * ones added to them, ranges are intersected.
+     */
+    fn zone(&self, sample: usize, generators: [&Generators; 4]) -> Option<Zone> {
+        let [zone, global, preset_zone, preset_global] = generators;
+        let instrument = |g: SF2Generator| {
+            zone.value(g)
+                .or_else(|| global.value(g))
+                .unwrap_or_else(|| g.default_value()) as i32
+        };
+        let preset = |g: SF2Generator| {
+            preset_zone
+                .value(g)
+                .or_else(|| preset_global.value(g))
+                .unwrap_or(0) as i32
+        };
+        let value = |g: SF2Generator| instrument(g) + preset(g);
+        let range = |g: SF2Generator| {
+            let of = |l: &Generators| l.range(g);
+            let a = of(zone).or_else(|| of(global)).unwrap_or((0, 127));
+            let b = of(preset_zone)
+                .or_else(|| of(preset_global))
+                .unwrap_or((0, 127));
+
+            (a.0.max(b.0), a.1.min(b.1))
+        };
+
+        let header = self.samples.get(sample)?;
+
+        // Address offsets are only allowed at the instrument level
+        let offset = |fine: SF2Generator, coarse: SF2Generator| {
+            instrument(fine) as i64 + instrument(coarse) as i64 * 32768
+        };
+        let start = header.start as i64
+            + offset(
+                SF2Generator::StartAddrsOffset,
+                SF2Generator::StartAddrsCoarseOffset,
+            );
+        let end = header.end as i64
+            + offset(
+                SF2Generator::EndAddrsOffset,
+                SF2Generator::EndAddrsCoarseOffset,
+            );
+        let (start, end) = (
+            start.clamp(0, self.data.len() as i64) as usize,
+            end.clamp(0, self.data.len() as i64) as usize,
+        );
+
+        if start >= end {
+            return None;
+        }
+
+        let loop_start = header.loop_start as i64
+            + offset(
+                SF2Generator::StartloopAddrsOffset,
+                SF2Generator::StartloopAddrsCoarseOffset,
+            )
+            - start as i64;
+        let loop_end = header.loop_end as i64
+            + offset(
+                SF2Generator::EndloopAddrsOffset,
+                SF2Generator::EndloopAddrsCoarseOffset,
+            )
+            - start as i64;
+
+        let mut z = Zone::new(self.sample(sample, start, end), 60);
+
+        z.keys = range(SF2Generator::KeyRange);
+        z.velocities = range(SF2Generator::VelRange);
+
+        z.root_key = match instrument(SF2Generator::OverridingRootKey) {
+            key @ 0..=127 => key as u8,
+            _ if header.original_pitch <= 127 => header.original_pitch,
+            _ => 60,
+        };
+        let cents = value(SF2Generator::CoarseTune) * 100
+            + value(SF2Generator::FineTune)
+            + header.pitch_correction as i32;
+        z.tune = -cents as f32;
+        z.key_tracking = value(SF2Generator::ScaleTuning) as f32 / 100.0;
+
+        z.gain = centibels(value(SF2Generator::InitialAttenuation));
+        z.pan = (value(SF2Generator::Pan) as f32 / 500.0).clamp(-1.0, 1.0);
+
+        z.loop_mode = match instrument(SF2Generator::SampleModes) & 3 {
+            1 => LoopMode::Forward,
+            3 => LoopMode::Sustain,
+            _ => LoopMode::None,
+        };
+        z.loop_start = loop_start.clamp(0, z.sample.len() as i64) as usize;
+        z.loop_end = loop_end.clamp(0, z.sample.len() as i64) as usize;
+
+        // The decay time is how long a fall to -100 dB takes
+        let sustain = value(SF2Generator::SustainVolEnv).clamp(0, 1440);
+        z.envelope = Some(Envelope {
+            delay_duration: timecents(value(SF2Generator::DelayVolEnv)),
+            attack_duration: timecents(value(SF2Generator::AttackVolEnv)),
+            hold_duration: timecents(value(SF2Generator::HoldVolEnv)),
+            decay_duration: timecents(value(SF2Generator::DecayVolEnv))
+                * (sustain as f32 / 1000.0).min(1.0),
+            sustain_level: centibels(sustain),
+            release_duration: timecents(value(SF2Generator::ReleaseVolEnv)),
+            curve: Curve::Exponential,
+        });
+
+        // Absolute cents over 8.176 Hz, 13500 and above leave the filter open
+        let cutoff = value(SF2Generator::InitialFilterFc);
+        if cutoff < 13500 {
+            let q = value(SF2Generator::InitialFilterQ).clamp(0, 960);
+
+            z.filter = Some((
+                8.176 * 2f32.powf(cutoff as f32 / 1200.0),
+                std::f32::consts::FRAC_1_SQRT_2 / centibels(q),
+            ));
+        }
+
+        Some(z)
+    }
+
+    // Sample points [start, end) as a shared Sample
+    fn sample(&self, header: usize, start: usize, end: usize) -> Rc<Sample> {
+        self.cache
+            .borrow_mut()
+            .entry((header, start, end))
+            .or_insert_with(|| {
+                let frames = self.data[start..end]
+                    .iter()
+                    .map(|v| {
+                        let v = *v as f32 / 32768.0;
+                        Stereo::new(v, v)
+                    })
+                    .collect();
+
+                Rc::new(Sample::new(frames, self.samples[header].sample_rate))
+            })
+            .clone()
+    }
+}
+
+// Seconds
+fn timecents(tc: i32) -> f32 {
+    2f32.powf(tc as f32 / 1200.0)
+}
+
+// Gain of an attenuation in centibels
+fn centibels(cb: i32) -> f32 {
+    10f32.powf(-cb as f32 / 200.0)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::Instrument;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        if body.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(b"LIST", &[kind.to_vec(), chunks.concat()].concat())
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(20, 0);
+        bytes
+    }
+
+    fn generator(operator: SF2Generator, amount: i16) -> Vec<u8> {
+        [(operator as u16).to_le_bytes(), amount.to_le_bytes()].concat()
+    }
+
+    fn range(operator: SF2Generator, low: u8, high: u8) -> Vec<u8> {
+        [(operator as u16).to_le_bytes(), [low, high]].concat()
+    }
+
+    // Zones starting at each generator index, the last one closing the list
+    fn bags(starts: &[u16]) -> Vec<u8> {
+        starts
+            .iter()
+            .flat_map(|g| [g.to_le_bytes(), [0, 0]].concat())
+            .collect()
+    }
+
+    /*
+     * Preset 0:5 with a global zone and a zone linked to instrument 0, which
+     * has a global zone, a zone linked to sample 0 and a stray zone with no
+     * sample. The sample has 1000 points, loops over [100, 900) and is an A4
+     * 5 cents sharp.
+     */
+    fn sound_font() -> Vec<u8> {
+        use SF2Generator::*;
+
+        let pgen = [
+            // Global
+            generator(CoarseTune, 1),
+            generator(FineTune, -5),
+            // Instrument 0, its fine tune replacing the global one
+            range(KeyRange, 60, 100),
+            generator(FineTune, 20),
+            generator(Instrument, 0),
+            // Terminal
+            generator(Instrument, 0),
+        ]
+        .concat();
+        let pbag = bags(&[0, 2, 5]);
+        let preset = |n: &str, program: u16, bag: u16| {
+            [
+                name(n),
+                program.to_le_bytes().to_vec(),
+                // Bank
+                vec![0; 2],
+                bag.to_le_bytes().to_vec(),
+                vec![0; 12],
+            ]
+            .concat()
+        };
+        let phdr = [preset("Piano", 5, 0), preset("EOP", 0, 2)].concat();
+
+        let igen = [
+            // Global
+            generator(InitialAttenuation, 60),
+            generator(SampleModes, 1),
+            generator(StartloopAddrsOffset, 10),
+            // Sample 0, its attenuation replacing the global one
+            range(KeyRange, 40, 80),
+            generator(InitialAttenuation, 100),
+            generator(StartAddrsOffset, 50),
+            generator(EndloopAddrsOffset, -20),
+            generator(SampleID, 0),
+            // Not global since it isn't first, and without a sample
+            generator(Pan, 500),
+            // Terminal
+            generator(SampleID, 0),
+        ]
+        .concat();
+        let ibag = bags(&[0, 3, 8, 9]);
+        let inst = [
+            name("Strings"),
+            0u16.to_le_bytes().to_vec(),
+            name("EOI"),
+            3u16.to_le_bytes().to_vec(),
+        ]
+        .concat();
+
+        let header = |n: &str, end: u32, pitch: u8, correction: i8| {
+            [
+                name(n),
+                [0, end, 100, 900, 22050]
+                    .iter()
+                    .flat_map(|v: &u32| v.to_le_bytes())
+                    .collect(),
+                vec![pitch, correction as u8, 0, 0, 0, 0],
+            ]
+            .concat()
+        };
+        let shdr = [header("A4", 1000, 69, 5), header("EOS", 0, 0, 0)].concat();
+
+        let smpl: Vec<u8> = (0..1000i16).flat_map(|v| v.to_le_bytes()).collect();
+
+        chunk(
+            b"RIFF",
+            &[
+                b"sfbk".to_vec(),
+                list(b"INFO", &[chunk(b"ifil", &[2, 0, 1, 0])]),
+                list(b"sdta", &[chunk(b"smpl", &smpl)]),
+                list(
+                    b"pdta",
+                    &[
+                        chunk(b"phdr", &phdr),
+                        chunk(b"pbag", &pbag),
+                        chunk(b"pmod", &[0; 10]),
+                        chunk(b"pgen", &pgen),
+                        chunk(b"inst", &inst),
+                        chunk(b"ibag", &ibag),
+                        chunk(b"imod", &[0; 10]),
+                        chunk(b"igen", &igen),
+                        chunk(b"shdr", &shdr),
+                    ],
+                ),
+            ]
+            .concat(),
+        )
+    }
+
+    #[test]
+    fn global_zones_are_detected() {
+        let font = SoundFont::parse(&sound_font()).unwrap();
+
+        assert_eq!(font.presets.len(), 1);
+        let preset = &font.presets[0];
+        assert_eq!(
+            (preset.name.as_str(), preset.bank, preset.program),
+            ("Piano", 0, 5)
+        );
+        assert_eq!(preset.global.get(SF2Generator::FineTune as u16), Some(-5));
+        assert_eq!(preset.zones.len(), 1);
+        assert_eq!(preset.zones[0].link, 0);
+
+        assert_eq!(font.instruments.len(), 1);
+        let instrument = &font.instruments[0];
+        assert_eq!(
+            instrument
+                .global
+                .get(SF2Generator::InitialAttenuation as u16),
+            Some(60)
+        );
+        // The stray zone is dropped
+        assert_eq!(instrument.zones.len(), 1);
+
+        assert_eq!(font.samples.len(), 1);
+        assert_eq!(font.samples[0].pitch_correction, 5);
+    }
+
+    #[test]
+    fn generator_precedence_and_loop_offsets() {
+        let font = SoundFont::parse(&sound_font()).unwrap();
+        let sampler = font.sampler(font.midi_preset(0, 0, 5).unwrap());
+
+        assert_eq!(sampler.zones.len(), 1);
+        let zone = &sampler.zones[0];
+
+        // Ranges are intersected
+        assert_eq!(zone.keys, (60, 80));
+        assert_eq!(zone.root_key, 69);
+        // Coarse from the preset global zone, fine from the preset zone, sample correction
+        assert_eq!(zone.tune, -125.0);
+        // Instrument zone over its global zone, 10 dB
+        assert!((zone.gain - 10f32.powf(-0.5)).abs() < 1e-6);
+        assert_eq!(zone.loop_mode, LoopMode::Forward);
+        assert_eq!(zone.pan, 0.0);
+
+        // Loop points are offset by their generators and counted from the new start
+        assert_eq!(zone.sample.len(), 950);
+        assert_eq!(zone.loop_start, 100 + 10 - 50);
+        assert_eq!(zone.loop_end, 900 - 20 - 50);
+    }
+
+    #[test]
+    fn midi_samplers_follow_program_changes() {
+        let font = SoundFont::parse(&sound_font()).unwrap();
+
+        // Channel 4 switches to program 5 and plays a C4, program 0 plays nothing
+        let events = [
+            0x00, 0xC3, 0x05, 0x00, 0x93, 0x3C, 0x64, 0x60, 0x83, 0x3C, 0x40, 0x00, 0xFF, 0x2F,
+            0x00,
+        ];
+        let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk".to_vec();
+        bytes.extend((events.len() as u32).to_be_bytes());
+        bytes.extend(events);
+        let midi = MIDIFile::from_bytes(&bytes).unwrap();
+
+        let samplers = font.midi_samplers(&midi);
+        assert_eq!(samplers.keys().collect::<Vec<_>>(), vec![&(3, 0, 5)]);
+
+        let notes = midi.program_notes(|channel, bank, program| {
+            let sampler = samplers.get(&(channel, bank, program))?;
+
+            Some(Instrument {
+                sampler: Some(sampler),
+                ..Instrument::DEFAULT
+            })
+        });
+
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].pitch.key(), 60);
+    }
+}
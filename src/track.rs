@@ -0,0 +1,42 @@
+use crate::{instrument::Note, io::Stereo, tuning::Tuning};
+
+// Processes the mix of a track one frame at a time
+pub trait Effect: std::fmt::Debug {
+    fn process(&mut self, v: Stereo) -> Stereo;
+}
+
+/*********************/
+// Notes mixed together then run through every effect, in order
+#[derive(Debug, Default)]
+pub struct Track<'a> {
+    pub notes: Vec<Note<'a>>,
+    pub effects: Vec<Box<dyn Effect + 'a>>,
+}
+
+impl<'a> Track<'a> {
+    pub fn new(notes: Vec<Note<'a>>) -> Self {
+        Self {
+            notes,
+            effects: vec![],
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_effect(mut self, effect: impl Effect + 'a) -> Self {
+        self.effects.push(Box::new(effect));
+        self
+    }
+
+    pub fn play(&mut self, t: f32, tuning: &Tuning) -> Stereo {
+        let v = self
+            .notes
+            .iter_mut()
+            .fold(Stereo::default(), |prev, n| prev + n.play(t, tuning));
+
+        self.effects.iter_mut().fold(v, |v, e| e.process(v))
+    }
+
+    pub fn is_active(&self, t: f32) -> bool {
+        self.notes.iter().any(|n| n.is_active(t))
+    }
+}
@@ -0,0 +1,511 @@
+/*
+ * Scala scale (.scl) and keyboard mapping (.kbm) support
+ * Formats: https://www.huygens-fokker.org/scala/scl_format.html
+ *          https://www.huygens-fokker.org/scala/help.htm#mappings
+ */
+
+use std::fs;
+
+use crate::pitch::Pitch;
+
+#[derive(Debug)]
+pub enum TuningError {
+    Io(std::io::Error),
+    // Malformed line, `line` counts from 1 like text editors
+    Parse { line: usize, message: String },
+    // The key the reference frequency is given for isn't mapped to a degree
+    UnmappedReference(u8),
+    // A scale needs at least one degree, its period
+    EmptyScale,
+}
+
+impl std::fmt::Display for TuningError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TuningError::Io(e) => write!(f, "I/O error: {}", e),
+            TuningError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            TuningError::UnmappedReference(key) => {
+                write!(f, "reference key {} isn't mapped to a scale degree", key)
+            }
+            TuningError::EmptyScale => write!(f, "scale has no degrees"),
+        }
+    }
+}
+
+impl std::error::Error for TuningError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TuningError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for TuningError {
+    fn from(e: std::io::Error) -> Self {
+        TuningError::Io(e)
+    }
+}
+
+// Non-comment lines of a Scala file, with their line number
+fn lines(source: &str) -> impl Iterator<Item = (usize, &str)> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.starts_with('!'))
+}
+
+/*********************/
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct Scale {
+    pub description: String,
+    // Cents above the tonic of degrees 1 to n, the last one is the period
+    pub degrees: Vec<f64>,
+}
+
+impl Scale {
+    // `divisions` equal steps to the octave, at least one
+    pub fn equal(divisions: usize) -> Result<Self, TuningError> {
+        if divisions == 0 {
+            return Err(TuningError::EmptyScale);
+        }
+
+        Ok(Self {
+            description: format!("{} equal divisions of the octave", divisions),
+            degrees: (1..=divisions)
+                .map(|i| 1200.0 * i as f64 / divisions as f64)
+                .collect(),
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn load(filename: &str) -> Result<Self, TuningError> {
+        Self::parse(&fs::read_to_string(filename)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, TuningError> {
+        let mut lines = lines(source);
+
+        let description = lines.next().map(|(_, l)| l.to_string()).unwrap_or_default();
+
+        let (line, count) = lines.next().ok_or(TuningError::Parse {
+            line: 0,
+            message: "missing number of notes".to_string(),
+        })?;
+        let count: usize = Self::first_word(count)
+            .parse()
+            .map_err(|_| TuningError::Parse {
+                line,
+                message: format!("invalid number of notes {:?}", count),
+            })?;
+
+        let mut degrees = vec![];
+
+        for (line, l) in lines.filter(|(_, l)| !l.is_empty()).take(count) {
+            degrees.push(
+                Self::parse_pitch(Self::first_word(l)).ok_or(TuningError::Parse {
+                    line,
+                    message: format!("invalid pitch {:?}", l),
+                })?,
+            );
+        }
+
+        if degrees.len() != count || count == 0 {
+            return Err(TuningError::Parse {
+                line,
+                message: format!("expected {} notes, found {}", count, degrees.len()),
+            });
+        }
+
+        Ok(Self {
+            description,
+            degrees,
+        })
+    }
+
+    fn first_word(line: &str) -> &str {
+        line.split_whitespace().next().unwrap_or("")
+    }
+
+    // Values with a period are cents, anything else is a ratio like "3/2" or "2"
+    fn parse_pitch(word: &str) -> Option<f64> {
+        if word.contains('.') {
+            return word.parse().ok();
+        }
+
+        let (n, d) = match word.split_once('/') {
+            Some((n, d)) => (n.parse::<f64>().ok()?, d.parse::<f64>().ok()?),
+            None => (word.parse::<f64>().ok()?, 1.0),
+        };
+
+        if n <= 0.0 || d <= 0.0 {
+            return None;
+        }
+
+        Some(1200.0 * (n / d).log2())
+    }
+
+    // Cents above the tonic of any degree, negative ones being below it
+    pub fn cents(&self, degree: i32) -> f64 {
+        let n = self.degrees.len() as i32;
+        let period = self.degrees[n as usize - 1];
+        let (octave, step) = (degree.div_euclid(n), degree.rem_euclid(n));
+
+        octave as f64 * period
+            + match step {
+                0 => 0.0,
+                s => self.degrees[s as usize - 1],
+            }
+    }
+}
+
+/*********************/
+#[derive(Debug, Clone)]
+pub struct KeyboardMapping {
+    pub first_key: u8,
+    pub last_key: u8,
+    // Key playing the scale tonic
+    pub middle_key: u8,
+    pub reference_key: u8,
+    pub reference_frequency: f64,
+    // Degree a full pattern of the map moves up by
+    pub octave_degree: i32,
+    // Degree of every key of the repeating pattern, None for unmapped keys.
+    // An empty map sends consecutive keys to consecutive degrees.
+    pub map: Vec<Option<i32>>,
+}
+
+impl KeyboardMapping {
+    // A pattern longer than the MIDI keyboard could never repeat
+    pub const MAX_MAP_SIZE: usize = 128;
+
+    // Consecutive keys on consecutive degrees, tonic on C4 and A4 at 440 Hz
+    pub fn linear() -> Self {
+        Self {
+            first_key: 0,
+            last_key: 127,
+            middle_key: 60,
+            reference_key: 69,
+            reference_frequency: Pitch::REFERENCE,
+            octave_degree: 0,
+            map: vec![],
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn load(filename: &str) -> Result<Self, TuningError> {
+        Self::parse(&fs::read_to_string(filename)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, TuningError> {
+        let mut lines = lines(source).filter(|(_, l)| !l.is_empty());
+        let mut last_line = 0;
+
+        let mut field = |name: &str| -> Result<(usize, String), TuningError> {
+            let (line, l) = lines.next().ok_or_else(|| TuningError::Parse {
+                line: last_line,
+                message: format!("missing {}", name),
+            })?;
+            last_line = line;
+
+            Ok((line, Scale::first_word(l).to_string()))
+        };
+
+        fn number<T: std::str::FromStr>(
+            (line, word): (usize, String),
+            name: &str,
+        ) -> Result<T, TuningError> {
+            word.parse().map_err(|_| TuningError::Parse {
+                line,
+                message: format!("invalid {} {:?}", name, word),
+            })
+        }
+
+        let size_field = field("map size")?;
+        let size_line = size_field.0;
+        let size: usize = number(size_field, "map size")?;
+
+        // Keeps a corrupt file from allocating a huge pattern
+        if size > Self::MAX_MAP_SIZE {
+            return Err(TuningError::Parse {
+                line: size_line,
+                message: format!("map size {} is over {}", size, Self::MAX_MAP_SIZE),
+            });
+        }
+
+        let first_key = number(field("first key")?, "first key")?;
+        let last_key = number(field("last key")?, "last key")?;
+        let middle_key = number(field("middle key")?, "middle key")?;
+        let reference_key = number(field("reference key")?, "reference key")?;
+        let reference_frequency = number(field("reference frequency")?, "reference frequency")?;
+        let octave_degree = number(field("octave degree")?, "octave degree")?;
+
+        let mut map = vec![];
+        for _ in 0..size {
+            // Trailing entries may be left out, they're unmapped
+            let entry = match field("map entry") {
+                Ok(e) => e,
+                Err(_) => break,
+            };
+
+            map.push(match entry.1.as_str() {
+                "x" | "X" => None,
+                _ => Some(number(entry, "map entry")?),
+            });
+        }
+        map.resize(size, None);
+
+        Ok(Self {
+            first_key,
+            last_key,
+            middle_key,
+            reference_key,
+            reference_frequency,
+            octave_degree,
+            map,
+        })
+    }
+
+    // Scale degree played by `key`, relative to the tonic
+    pub fn degree(&self, key: u8) -> Option<i32> {
+        if key < self.first_key || key > self.last_key {
+            return None;
+        }
+
+        let offset = key as i32 - self.middle_key as i32;
+
+        if self.map.is_empty() {
+            return Some(offset);
+        }
+
+        let size = self.map.len() as i32;
+
+        self.map[offset.rem_euclid(size) as usize]
+            .map(|d| offset.div_euclid(size) * self.octave_degree + d)
+    }
+}
+
+/*********************/
+// Maps keys to frequencies through a scale laid on the keyboard
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    pub scale: Scale,
+    pub mapping: KeyboardMapping,
+    reference_cents: f64,
+}
+
+impl Default for Tuning {
+    // Twelve-tone equal temperament, A4 = 440 Hz
+    fn default() -> Self {
+        Self::equal(12).expect("twelve divisions make a scale")
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: Scale, mapping: KeyboardMapping) -> Result<Self, TuningError> {
+        if scale.degrees.is_empty() {
+            return Err(TuningError::EmptyScale);
+        }
+
+        let reference = mapping
+            .degree(mapping.reference_key)
+            .ok_or(TuningError::UnmappedReference(mapping.reference_key))?;
+
+        Ok(Self {
+            reference_cents: scale.cents(reference),
+            scale,
+            mapping,
+        })
+    }
+
+    // `divisions` equal steps to the octave on consecutive keys, A4 = 440 Hz
+    pub fn equal(divisions: usize) -> Result<Self, TuningError> {
+        Self::new(Scale::equal(divisions)?, KeyboardMapping::linear())
+    }
+
+    #[allow(dead_code)]
+    pub fn load(scl: &str, kbm: Option<&str>) -> Result<Self, TuningError> {
+        let mapping = match kbm {
+            Some(kbm) => KeyboardMapping::load(kbm)?,
+            None => KeyboardMapping::linear(),
+        };
+
+        Self::new(Scale::load(scl)?, mapping)
+    }
+
+    pub fn key_frequency(&self, key: u8) -> Option<f64> {
+        let degree = self.mapping.degree(key)?;
+
+        Some(
+            self.mapping.reference_frequency
+                * 2f64.powf((self.scale.cents(degree) - self.reference_cents) / 1200.0),
+        )
+    }
+
+    // Frequency of `pitch`, None when its key isn't mapped
+    pub fn frequency(&self, pitch: Pitch) -> Option<f32> {
+        self.key_frequency(pitch.key()).map(|f| f as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Just intonation major scale
+    const JUST: &str = "! just.scl
+!
+Just major
+ 7
+!
+9/8
+5/4
+4/3
+3/2
+5/3
+15/8
+1200.0 octave
+";
+
+    // White keys on the seven degrees, A4 = 440 Hz
+    const WHITE_KEYS: &str = "! white keys
+12
+0
+127
+60
+69
+440.0
+7
+! C to B
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn scale_reads_cents_and_ratios() {
+        let scale = Scale::parse(JUST).unwrap();
+
+        assert_eq!(scale.description, "Just major");
+        assert_eq!(scale.degrees.len(), 7);
+        assert_close(scale.degrees[0], 1200.0 * (9.0f64 / 8.0).log2());
+        assert_close(scale.degrees[3], 1200.0 * 1.5f64.log2());
+        assert_close(scale.degrees[6], 1200.0);
+
+        // Degrees below the tonic and past the period
+        assert_close(scale.cents(-1), 1200.0 * (15.0f64 / 16.0).log2());
+        assert_close(scale.cents(7), 1200.0);
+        assert_close(scale.cents(11), 2400.0 + scale.degrees[3] - 1200.0);
+    }
+
+    #[test]
+    fn scale_skips_comment_and_blank_lines() {
+        let scale =
+            Scale::parse("! half.scl\nHalf octaves\n! count next\n 2\n!\n600.\n\n2/1\n").unwrap();
+
+        assert_eq!(scale.description, "Half octaves");
+        assert_eq!(scale.degrees, vec![600.0, 1200.0]);
+    }
+
+    #[test]
+    fn scale_rejects_count_mismatch() {
+        match Scale::parse("short\n3\n100.0\n2/1\n") {
+            Err(TuningError::Parse { line, message }) => {
+                assert_eq!(line, 2);
+                assert_eq!(message, "expected 3 notes, found 2");
+            }
+            other => panic!("{:?}", other),
+        }
+
+        assert!(matches!(
+            Scale::parse("bad\n2\n100.0\n-3/2\n"),
+            Err(TuningError::Parse { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn equal_rejects_zero_divisions() {
+        assert!(matches!(Scale::equal(0), Err(TuningError::EmptyScale)));
+        assert!(matches!(Tuning::equal(0), Err(TuningError::EmptyScale)));
+        assert_eq!(Tuning::equal(19).unwrap().scale.degrees.len(), 19);
+
+        let empty = Scale {
+            description: String::new(),
+            degrees: vec![],
+        };
+        assert!(matches!(
+            Tuning::new(empty, KeyboardMapping::linear()),
+            Err(TuningError::EmptyScale)
+        ));
+    }
+
+    #[test]
+    fn mapping_leaves_x_keys_unmapped() {
+        let mapping = KeyboardMapping::parse(WHITE_KEYS).unwrap();
+        assert_eq!(mapping.map.len(), 12);
+        assert_eq!(mapping.degree(60), Some(0));
+        assert_eq!(mapping.degree(61), None);
+        assert_eq!(mapping.degree(71), Some(6));
+        assert_eq!(mapping.degree(72), Some(7));
+        assert_eq!(mapping.degree(59), Some(-1));
+
+        let tuning = Tuning::new(Scale::parse(JUST).unwrap(), mapping).unwrap();
+        assert_close(tuning.key_frequency(69).unwrap(), 440.0);
+        assert_close(tuning.key_frequency(60).unwrap(), 264.0);
+        assert_close(tuning.key_frequency(67).unwrap(), 396.0);
+        assert_close(tuning.key_frequency(72).unwrap(), 528.0);
+        assert_eq!(tuning.key_frequency(61), None);
+        assert_eq!(tuning.frequency(Pitch::A4S), None);
+    }
+
+    #[test]
+    fn mapping_pads_missing_entries_as_unmapped() {
+        let mapping = KeyboardMapping::parse("3\n0\n127\n60\n60\n261.6\n3\n0\n1\n").unwrap();
+
+        assert_eq!(mapping.map, vec![Some(0), Some(1), None]);
+        assert_eq!(mapping.degree(62), None);
+        assert_eq!(mapping.degree(64), Some(4));
+    }
+
+    #[test]
+    fn mapping_rejects_oversized_maps() {
+        let full = KeyboardMapping::parse("128\n0\n127\n60\n69\n440.0\n12\n").unwrap();
+        assert_eq!(full.map.len(), 128);
+
+        match KeyboardMapping::parse("! huge\n4000000000\n0\n127\n60\n69\n440.0\n12\n") {
+            Err(TuningError::Parse { line, message }) => {
+                assert_eq!(line, 2);
+                assert_eq!(message, "map size 4000000000 is over 128");
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unmapped_reference_key_is_an_error() {
+        let mapping = KeyboardMapping {
+            reference_key: 70,
+            ..KeyboardMapping::parse(WHITE_KEYS).unwrap()
+        };
+
+        assert!(matches!(
+            Tuning::new(Scale::parse(JUST).unwrap(), mapping),
+            Err(TuningError::UnmappedReference(70))
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::io::WAV;
+
+/*
+ * Single-cycle waveforms, one table per frame and per octave so that no
+ * harmonic above Nyquist is ever played. Frames are morphed by position.
+ */
+#[derive(Debug, Clone)]
+pub struct Wavetable {
+    // levels[frame][level], level 0 has every harmonic and each next one half of them
+    levels: Vec<Vec<Vec<f32>>>,
+    size: usize,
+}
+
+#[allow(dead_code)]
+impl Wavetable {
+    // Samples per cycle, frames of any other length are resampled to it
+    pub const SIZE: usize = 2048;
+
+    // `frames` single cycles, any length
+    pub fn from_frames(frames: &[Vec<f32>]) -> Self {
+        let spectra = frames
+            .iter()
+            .filter(|f| !f.is_empty())
+            .map(|f| {
+                let mut bins: Vec<(f64, f64)> = Self::resample(f, Self::SIZE)
+                    .iter()
+                    .map(|v| (*v as f64, 0.0))
+                    .collect();
+                fft(&mut bins, false);
+                bins
+            })
+            .collect();
+
+        Self::from_spectra(spectra)
+    }
+
+    // Frames as sine amplitudes of harmonics 1, 2, 3...
+    pub fn from_harmonics(frames: &[Vec<f32>]) -> Self {
+        let n = Self::SIZE;
+        let spectra = frames
+            .iter()
+            .map(|harmonics| {
+                let mut bins = vec![(0.0, 0.0); n];
+
+                for (i, a) in harmonics.iter().enumerate().take(n / 2 - 1) {
+                    let a = *a as f64 * n as f64 / 2.0;
+                    bins[i + 1] = (0.0, -a);
+                    bins[n - i - 1] = (0.0, a);
+                }
+
+                bins
+            })
+            .collect();
+
+        Self::from_spectra(spectra)
+    }
+
+    /*
+     * Cuts a WAV file into frames of `frame_size` samples, or uses the whole
+     * file as a single cycle when None
+     */
+    pub fn load(filename: &str, frame_size: Option<usize>) -> std::io::Result<Self> {
+        let samples = WAV::load(filename)?.mono();
+        let frame_size = frame_size.unwrap_or(samples.len()).max(1);
+
+        let frames: Vec<Vec<f32>> = samples
+            .chunks_exact(frame_size)
+            .map(|c| c.to_vec())
+            .collect();
+
+        if frames.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file is shorter than one frame",
+            ));
+        }
+
+        Ok(Self::from_frames(&frames))
+    }
+
+    fn from_spectra(spectra: Vec<Vec<(f64, f64)>>) -> Self {
+        let n = Self::SIZE;
+        let mut levels = vec![];
+
+        for spectrum in spectra {
+            let mut frame = vec![];
+            let mut harmonics = n / 2 - 1;
+
+            while harmonics >= 1 {
+                let mut bins = spectrum.clone();
+
+                // No DC, and nothing above the harmonics this level keeps
+                bins[0] = (0.0, 0.0);
+                for (k, b) in bins.iter_mut().enumerate().skip(1) {
+                    if k > harmonics && k < n - harmonics {
+                        *b = (0.0, 0.0);
+                    }
+                }
+
+                fft(&mut bins, true);
+                frame.push(bins.iter().map(|(re, _)| (*re / n as f64) as f32).collect());
+
+                harmonics /= 2;
+            }
+
+            levels.push(frame);
+        }
+
+        if levels.is_empty() {
+            levels.push(vec![vec![0.0; n]]);
+        }
+
+        Self { levels, size: n }
+    }
+
+    fn resample(frame: &[f32], size: usize) -> Vec<f32> {
+        if frame.len() == size {
+            return frame.to_vec();
+        }
+
+        (0..size)
+            .map(|i| {
+                let x = i as f64 * frame.len() as f64 / size as f64;
+                let (j, fraction) = (x as usize, x.fract() as f32);
+                let next = frame[(j + 1) % frame.len()];
+
+                frame[j] + (next - frame[j]) * fraction
+            })
+            .collect()
+    }
+
+    pub fn frames(&self) -> usize {
+        self.levels.len()
+    }
+
+    /*
+     * Value at `phase` when playing `dt` of a cycle per sample, `position`
+     * in [0, 1] morphs from the first frame to the last one
+     */
+    pub fn sample(&self, phase: f64, dt: f64, position: f32) -> f64 {
+        let level = self.level(dt);
+
+        let x = position.clamp(0.0, 1.0) as f64 * (self.frames() - 1) as f64;
+        let (frame, fraction) = (x as usize, x.fract());
+
+        let a = self.read(frame, level, phase);
+
+        if fraction == 0.0 {
+            return a;
+        }
+
+        a + (self.read(frame + 1, level, phase) - a) * fraction
+    }
+
+    // Highest harmonic that stays under Nyquist at `dt`, then the level that fits it
+    fn level(&self, dt: f64) -> usize {
+        let allowed = 0.5 / dt.max(1e-9);
+        let top = self.size / 2 - 1;
+
+        // Counted down like from_spectra does, halving with the remainder dropped
+        (0..usize::BITS as usize)
+            .find(|level| (top >> level) as f64 <= allowed)
+            .unwrap_or(0)
+    }
+
+    // Cubic Hermite interpolation in one table
+    fn read(&self, frame: usize, level: usize, phase: f64) -> f64 {
+        let levels = &self.levels[frame.min(self.frames() - 1)];
+        let table = &levels[level.min(levels.len() - 1)];
+
+        let x = phase.rem_euclid(1.0) * self.size as f64;
+        let i = x as usize;
+        let t = x.fract();
+
+        let at = |k: isize| table[(i as isize + k).rem_euclid(self.size as isize) as usize] as f64;
+
+        hermite([at(-1), at(0), at(1), at(2)], t)
+    }
+}
+
+// Catmull-Rom spline through `y` at `x` in [0, 1) between y[1] and y[2]
+pub fn hermite(y: [f64; 4], x: f64) -> f64 {
+    let [y0, y1, y2, y3] = y;
+
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+
+    ((c3 * x + c2) * x + c1) * x + y1
+}
+
+// In place radix-2 FFT of (re, im) pairs, the length must be a power of two
+fn fft(bins: &mut [(f64, f64)], inverse: bool) {
+    let n = bins.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+
+        if i < j {
+            bins.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+
+    while length <= n {
+        let angle = sign * 2.0 * std::f64::consts::PI / length as f64;
+
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (wr, wi) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+                let (ar, ai) = bins[start + k];
+                let (br, bi) = bins[start + k + length / 2];
+                let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+
+                bins[start + k] = (ar + tr, ai + ti);
+                bins[start + k + length / 2] = (ar - tr, ai - ti);
+            }
+        }
+
+        length <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::TAU;
+
+    use super::*;
+
+    #[test]
+    fn hermite_passes_through_its_middle_points() {
+        let y = [0.3, -0.5, 0.8, 0.1];
+
+        assert_eq!(hermite(y, 0.0), -0.5);
+        assert!((hermite(y, 1.0) - 0.8).abs() < 1e-12);
+
+        // Straight lines stay straight
+        for x in [0.25, 0.5, 0.75] {
+            assert!((hermite([1.0, 2.0, 3.0, 4.0], x) - (2.0 + x)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn mip_level_keeps_harmonics_under_nyquist() {
+        let table = Wavetable::from_harmonics(&[vec![1.0]]);
+        let nyquist = WAV::SAMPLE_RATE as f64 / 2.0;
+        // Level k keeps the first 1023 >> k harmonics
+        let harmonics = |level: usize| (Wavetable::SIZE / 2 - 1) >> level;
+
+        assert_eq!(table.level(20.0 / WAV::SAMPLE_RATE as f64), 0);
+        assert_eq!(table.level(44.0 / WAV::SAMPLE_RATE as f64), 2);
+        assert_eq!(table.level(440.0 / WAV::SAMPLE_RATE as f64), 5);
+
+        for f in (25..12000).step_by(25) {
+            let level = table.level(f as f64 / WAV::SAMPLE_RATE as f64);
+
+            // The richest level that doesn't alias
+            assert!(harmonics(level) as f64 * f as f64 <= nyquist, "{} Hz", f);
+            if level > 0 {
+                assert!(harmonics(level - 1) as f64 * f as f64 > nyquist, "{} Hz", f);
+            }
+        }
+    }
+
+    #[test]
+    fn position_morphs_linearly_between_frames() {
+        // A sine, then its octave
+        let table = Wavetable::from_harmonics(&[vec![1.0], vec![0.0, 1.0]]);
+        let dt = 440.0 / WAV::SAMPLE_RATE as f64;
+
+        for phase in [0.05, 0.125, 0.3, 0.61, 0.9] {
+            let first = (TAU * phase).sin();
+            let last = (2.0 * TAU * phase).sin();
+
+            for position in [0.0, 0.25, 0.5, 0.8, 1.0] {
+                let expected = first + (last - first) * position as f64;
+                let v = table.sample(phase, dt, position);
+
+                assert!((v - expected).abs() < 1e-4, "{} at {}", v, position);
+            }
+        }
+    }
+}